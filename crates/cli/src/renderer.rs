@@ -55,6 +55,8 @@ pub struct ParticleRenderer {
     render_pipeline: wgpu::RenderPipeline,
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
+    // Kept alive for the lifetime of the bind group that references it
+    #[allow(dead_code)]
     uniform_buffer: wgpu::Buffer,
     bind_group: wgpu::BindGroup,
     texture: wgpu::Texture,
@@ -319,6 +321,7 @@ impl ParticleRenderer {
         let bytes_per_pixel = 4u32; // RGBA
         let unpadded_bytes_per_row = self.width * bytes_per_pixel;
         let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        #[allow(clippy::manual_div_ceil)]
         let padded_bytes_per_row = (unpadded_bytes_per_row + align - 1) / align * align;
 
         // Create a buffer to copy texture data to
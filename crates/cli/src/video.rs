@@ -77,6 +77,10 @@ pub fn generate_fluid_animation(
     frame_gen.save_frames(output_dir)?;
     let total_steps = frames * step_interval;
     println!("Simulation completed: {} frames, {} total steps", frames, total_steps);
+    println!(
+        "Neighbor lists rebuilt in {:.1}% of steps",
+        100.0 * state.diagnostics.neighbor_rebuild_frequency()
+    );
     Ok(())
 }
 
@@ -111,6 +115,10 @@ pub fn generate_fluid_animation_cpu(
     frame_gen.save_frames(output_dir)?;
     let total_steps = frames * step_interval;
     println!("Simulation completed: {} frames, {} total steps", frames, total_steps);
+    println!(
+        "Neighbor lists rebuilt in {:.1}% of steps",
+        100.0 * state.diagnostics.neighbor_rebuild_frequency()
+    );
     Ok(())
 }
 
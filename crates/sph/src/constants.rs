@@ -1,3 +1,10 @@
+use crate::spatial_hash::SpatialIndex;
+use crate::gas_dynamics::FluidModel;
+use crate::equation_of_state::EquationOfStateKind;
+use crate::rheology::ViscosityModel;
+use crate::continuity::DensityEvolution;
+use crate::density_filter::DensityFilter;
+
 #[derive(Debug, Clone, Copy)]
 pub struct CalculationParameters {
     pub timestep: f64,
@@ -8,8 +15,76 @@ pub struct CalculationParameters {
     pub box_min: f64,
     pub box_max: f64,
     pub gravity: f64,
+    pub self_gravity: bool,
+    pub gravitational_constant: f64,
+    pub opening_angle: f64,
+    pub gravity_softening: f64,
     pub tait_c: f64,
     pub tait_gamma: f64,
+    pub equation_of_state: EquationOfStateKind,
+    pub background_pressure: f64,
+    pub stiffened_pressure: f64,
+    pub verlet_lists: bool,
+    pub verlet_skin: f64,
+    pub spatial_index: SpatialIndex,
+    pub adaptive_smoothing: bool,
+    pub target_neighbors: f64,
+    pub fluid_model: FluidModel,
+    pub density_evolution: DensityEvolution,
+    pub density_diffusion: f64,
+    pub density_reinitialization: bool,
+    pub density_filter: DensityFilter,
+    pub density_filter_interval: usize,
+    pub kernel_gradient_correction: bool,
+    pub particle_shifting: bool,
+    pub shifting_coefficient: f64,
+    pub xsph: bool,
+    pub xsph_epsilon: f64,
+    pub free_surface_detection: bool,
+    pub viscosity: f64,
+    pub rheology: ViscosityModel,
+    pub turbulence: bool,
+    pub smagorinsky_constant: f64,
+    pub blin_constant: f64,
+    pub vorticity_confinement: bool,
+    pub confinement_strength: f64,
+    pub vorticity_output: bool,
+    pub elastic_solid: bool,
+    pub solid_region: [[f64; 2]; 2],
+    pub youngs_modulus: f64,
+    pub poisson_ratio: f64,
+    pub artificial_stress: f64,
+    pub granular: bool,
+    pub friction_angle: f64,
+    pub cohesion: f64,
+    pub multiphase: bool,
+    pub phase_density_ratio: f64,
+    pub phase_viscosities: [f64; 2],
+    pub phase_interface_height: f64,
+    pub heat_transfer: bool,
+    pub thermal_conductivity: f64,
+    pub specific_heat: f64,
+    pub thermal_expansion: f64,
+    pub reference_temperature: f64,
+    pub floor_temperature: f64,
+    pub ceiling_temperature: f64,
+    pub thermal_wall_thickness: f64,
+    pub gas_gamma: f64,
+    pub gas_initial_energy: f64,
+    pub viscosity_alpha: f64,
+    pub viscosity_beta: f64,
+}
+
+impl CalculationParameters {
+    /// Radius used when building neighbor lists. With Verlet lists enabled the
+    /// lists are built with an extra skin so they stay valid for several steps.
+    pub const fn neighbor_search_radius(&self) -> f64 {
+        if self.verlet_lists {
+            self.smoothing_radius + self.verlet_skin
+        } else {
+            self.smoothing_radius
+        }
+    }
 }
 
 pub const GLOBALS: CalculationParameters = CalculationParameters {
@@ -21,9 +96,73 @@ pub const GLOBALS: CalculationParameters = CalculationParameters {
     box_min: -1.6,
     box_max: 1.6,
     gravity: -200.0,
+    self_gravity: false,
+    gravitational_constant: 1.0,
+    opening_angle: 0.5,
+    gravity_softening: 0.05,
     tait_c: 10.0,
     tait_gamma: 7.0,
+    equation_of_state: EquationOfStateKind::Tait,
+    background_pressure: 0.0,
+    stiffened_pressure: 0.0,
+    verlet_lists: false,
+    verlet_skin: 0.06,
+    spatial_index: SpatialIndex::Dense,
+    adaptive_smoothing: false,
+    target_neighbors: 50.0,
+    fluid_model: FluidModel::WeaklyCompressible,
+    density_evolution: DensityEvolution::Summation,
+    density_diffusion: 0.1,
+    density_reinitialization: false,
+    density_filter: DensityFilter::Shepard,
+    density_filter_interval: 30,
+    kernel_gradient_correction: false,
+    particle_shifting: false,
+    shifting_coefficient: 2.0,
+    xsph: false,
+    xsph_epsilon: 0.5,
+    free_surface_detection: false,
+    viscosity: 0.0,
+    rheology: ViscosityModel::Newtonian,
+    turbulence: false,
+    smagorinsky_constant: 0.12,
+    blin_constant: 0.0066,
+    vorticity_confinement: false,
+    confinement_strength: 2.0,
+    vorticity_output: false,
+    elastic_solid: false,
+    solid_region: [[-0.6, -0.2], [0.6, 0.4]],
+    youngs_modulus: 2000.0,
+    poisson_ratio: 0.3,
+    artificial_stress: 0.3,
+    granular: false,
+    friction_angle: 30.0,
+    cohesion: 0.0,
+    multiphase: false,
+    phase_density_ratio: 10.0,
+    phase_viscosities: [0.002, 0.02],
+    phase_interface_height: 0.0,
+    heat_transfer: false,
+    thermal_conductivity: 0.01,
+    specific_heat: 1.0,
+    thermal_expansion: 0.05,
+    reference_temperature: 0.0,
+    floor_temperature: 1.0,
+    ceiling_temperature: -1.0,
+    thermal_wall_thickness: 0.1,
+    gas_gamma: 1.4,
+    gas_initial_energy: 500.0,
+    viscosity_alpha: 1.0,
+    viscosity_beta: 2.0,
 };
 
+// Kernel gradient correction is only applied to the fixed-radius pressure
+// force of a single weakly compressible phase
+const _: () = assert!(
+    !GLOBALS.kernel_gradient_correction
+        || (matches!(GLOBALS.fluid_model, FluidModel::WeaklyCompressible) && !GLOBALS.adaptive_smoothing && !GLOBALS.multiphase),
+    "kernel_gradient_correction is not supported with adaptive smoothing, multiphase or gas forces"
+);
+
 pub const N: usize = GLOBALS.num_particles;
 pub const ARENA_SIZE: usize = N * 14;
\ No newline at end of file
@@ -0,0 +1,171 @@
+use crate::kernel::kernel_nd;
+use crate::material::Material;
+
+/// Filter used to periodically reinitialize evolved densities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DensityFilter {
+    /// Zeroth order, `rho_i = sum_j m_j W_ij / sum_j V_j W_ij`; restores a
+    /// uniform density exactly, also at free surfaces
+    Shepard,
+    /// First order moving least squares of Colagrossi & Landrini (2003);
+    /// restores a linearly varying density exactly
+    MovingLeastSquares,
+}
+
+/// Borrowed per-particle fields read by the density filters.
+pub struct FilterParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// Filtered density of every particle, interpolated from the particles of its
+/// own material including itself. Particles that do not interact keep their
+/// density.
+pub fn filter_densities(
+    particles: &FilterParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    filter: DensityFilter,
+    filtered: &mut [f32],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+    let n = rho.len();
+
+    // Moments of the kernel: sum_j V_j W_ij [1, r_ij] (x) [1, r_ij], and the
+    // mass-weighted column sum_j m_j W_ij [1, r_ij]
+    let mut moments = vec![[[0.0_f64; 4]; 4]; n];
+    let mut masses = vec![[0.0_f64; 4]; n];
+
+    let mut add = |i: usize, j: usize, w: f64, r: [f64; 4]| {
+        let volume = (materials[particles.material[j]].mass / rho[j]) as f64;
+        let mass = materials[particles.material[j]].mass as f64;
+        for a in 0..4 {
+            masses[i][a] += mass * w * r[a];
+            for b in 0..4 {
+                moments[i][a][b] += volume * w * r[a] * r[b];
+            }
+        }
+    };
+
+    for i in 0..n {
+        if materials[particles.material[i]].interacts() && rho[i] > 0.0 {
+            add(i, i, kernel_nd(0.0, inv_h, dim), [1.0, 0.0, 0.0, 0.0]);
+        }
+    }
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let material_i = &materials[particles.material[i]];
+            if particles.material[i] != particles.material[j] || !material_i.interacts() || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+            if r2 >= smoothing_radius * smoothing_radius {
+                continue;
+            }
+
+            let w = kernel_nd(r2.sqrt() as f64, inv_h, dim);
+            let r = dr.map(|c| c as f64);
+            add(i, j, w, [1.0, r[0], r[1], r[2]]);
+            add(j, i, w, [1.0, -r[0], -r[1], -r[2]]);
+        }
+    }
+
+    for i in 0..n {
+        filtered[i] = rho[i];
+        if moments[i][0][0] <= 0.0 {
+            continue;
+        }
+        let shepard = masses[i][0] / moments[i][0][0];
+        filtered[i] = match filter {
+            DensityFilter::Shepard => shepard as f32,
+            // rho_i = sum_j m_j W_ij beta . [1, r_ij] with A beta = e_1; an
+            // ill-conditioned A, as for a particle with few neighbors, falls
+            // back to Shepard
+            DensityFilter::MovingLeastSquares => match solve(&moments[i], dim + 1) {
+                Some(beta) => (0..=dim).map(|a| beta[a] * masses[i][a]).sum::<f64>() as f32,
+                None => shepard as f32,
+            },
+        };
+    }
+}
+
+/// First column of the inverse of the leading `size` x `size` block of
+/// `matrix`, by Gauss-Jordan elimination with partial pivoting.
+fn solve(matrix: &[[f64; 4]; 4], size: usize) -> Option<[f64; 4]> {
+    let mut a = *matrix;
+    let mut b = [1.0, 0.0, 0.0, 0.0];
+    let scale = (0..size).map(|k| a[k][k].abs()).fold(0.0, f64::max);
+
+    for column in 0..size {
+        let pivot = (column..size).max_by(|&p, &q| a[p][column].abs().total_cmp(&a[q][column].abs()))?;
+        if a[pivot][column].abs() <= 1e-6 * scale {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        let pivot_row = a[column];
+        for row in (0..size).filter(|&row| row != column) {
+            let factor = a[row][column] / pivot_row[column];
+            for (value, pivot) in a[row].iter_mut().zip(pivot_row).take(size).skip(column) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+    Some(std::array::from_fn(|k| if k < size { b[k] / a[k][k] } else { 0.0 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{half_neighbor_lists, square_lattice, water, SMOOTHING_RADIUS};
+
+    const SIDE: usize = 12;
+
+    /// Filters the density field `density` sampled on a square lattice.
+    fn filter_lattice(density: impl Fn(f32, f32) -> f32, filter: DensityFilter) -> (Vec<f32>, Vec<f32>) {
+        let position = square_lattice(SIDE);
+        let n = SIDE * SIDE;
+        let neighbors = half_neighbor_lists(&position);
+
+        let rho: Vec<f32> = (0..n).map(|i| density(position[0][i], position[1][i])).collect();
+        let material = vec![0; n];
+        let particles = FilterParticles {
+            position: [&position[0], &position[1], &position[2]],
+            rho: &rho,
+            material: &material,
+        };
+        let mut filtered = vec![0.0; n];
+        filter_densities(&particles, &[water()], &neighbors, SMOOTHING_RADIUS, 2, filter, &mut filtered);
+        (rho, filtered)
+    }
+
+    #[test]
+    fn shepard_keeps_uniform_density_at_the_edges() {
+        let (rho, filtered) = filter_lattice(|_, _| 1.3, DensityFilter::Shepard);
+        for (i, (rho, filtered)) in rho.iter().zip(&filtered).enumerate() {
+            assert!((rho - filtered).abs() < 1e-5, "particle {i}: {filtered}");
+        }
+    }
+
+    #[test]
+    fn moving_least_squares_keeps_linear_density() {
+        let (rho, filtered) = filter_lattice(|x, y| 1.0 + 0.2 * x - 0.1 * y, DensityFilter::MovingLeastSquares);
+        for (i, (rho, filtered)) in rho.iter().zip(&filtered).enumerate() {
+            assert!((rho - filtered).abs() < 1e-4, "particle {i}: {rho} {filtered}");
+        }
+    }
+
+    #[test]
+    fn shepard_is_only_zeroth_order_at_the_edges() {
+        let (rho, filtered) = filter_lattice(|x, _| 1.0 + 0.2 * x, DensityFilter::Shepard);
+        assert!((rho[0] - filtered[0]).abs() > 1e-3);
+    }
+}
@@ -0,0 +1,16 @@
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub steps: usize,
+    pub neighbor_rebuilds: usize,
+}
+
+impl Diagnostics {
+    /// Fraction of timesteps in which the neighbor lists were rebuilt.
+    pub fn neighbor_rebuild_frequency(&self) -> f64 {
+        if self.steps == 0 {
+            0.0
+        } else {
+            self.neighbor_rebuilds as f64 / self.steps as f64
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use crate::kd_tree::KdTree;
+use crate::kernel::{d_kernel_nd, kernel_nd};
+use crate::material::Material;
+use crate::neighbor_search::NeighborSearch;
+
+pub type Matrix3 = [[f32; 3]; 3];
+
+pub const IDENTITY: Matrix3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// Iterations of the polar decomposition and the Jacobi eigensolver
+const POLAR_ITERATIONS: usize = 20;
+const JACOBI_SWEEPS: usize = 8;
+
+/// Elastic moduli of a solid material.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Elasticity {
+    pub youngs_modulus: f32,
+    pub poisson_ratio: f32,
+    /// Strength `epsilon` of Monaghan's artificial stress against the
+    /// tensile instability
+    pub artificial_stress: f32,
+}
+
+impl Elasticity {
+    /// Lamé parameters `(lambda, mu)`.
+    pub fn lame(&self) -> (f32, f32) {
+        let e = self.youngs_modulus;
+        let nu = self.poisson_ratio;
+        (e * nu / ((1.0 + nu) * (1.0 - 2.0 * nu)), e / (2.0 * (1.0 + nu)))
+    }
+}
+
+fn mul(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    std::array::from_fn(|r| std::array::from_fn(|c| (0..3).map(|k| a[r][k] * b[k][c]).sum()))
+}
+
+pub(crate) fn mul_vector(a: &Matrix3, v: [f32; 3]) -> [f32; 3] {
+    std::array::from_fn(|r| (0..3).map(|k| a[r][k] * v[k]).sum())
+}
+
+pub(crate) fn transpose(a: &Matrix3) -> Matrix3 {
+    std::array::from_fn(|r| std::array::from_fn(|c| a[c][r]))
+}
+
+pub fn determinant(a: &Matrix3) -> f32 {
+    a[0][0] * (a[1][1] * a[2][2] - a[1][2] * a[2][1]) - a[0][1] * (a[1][0] * a[2][2] - a[1][2] * a[2][0])
+        + a[0][2] * (a[1][0] * a[2][1] - a[1][1] * a[2][0])
+}
+
+pub(crate) fn inverse(a: &Matrix3) -> Option<Matrix3> {
+    let det = determinant(a);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+    // Transposed cofactors over the determinant
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        a[r1][c1] * a[r2][c2] - a[r1][c2] * a[r2][c1]
+    };
+    Some(std::array::from_fn(|r| std::array::from_fn(|c| cofactor(c, r) / det)))
+}
+
+/// Rotation `R` of the polar decomposition `F = R S`, by Higham's iteration
+/// `R <- (R + R^-T) / 2`. Inverted or degenerate `F` yields the identity.
+pub fn rotation(f: &Matrix3) -> Matrix3 {
+    if determinant(f) <= 0.0 {
+        return IDENTITY;
+    }
+    let mut r = *f;
+    for _ in 0..POLAR_ITERATIONS {
+        let Some(inv) = inverse(&r) else { return IDENTITY };
+        let inv_t = transpose(&inv);
+        r = std::array::from_fn(|a| std::array::from_fn(|b| 0.5 * (r[a][b] + inv_t[a][b])));
+    }
+    r
+}
+
+/// First Piola-Kirchhoff stress of the corotated linear model,
+/// `P = 2 mu (F - R) + lambda tr(R^T F - I) R`.
+pub fn corotated_stress(f: &Matrix3, lame: (f32, f32)) -> Matrix3 {
+    let (lambda, mu) = lame;
+    let r = rotation(f);
+    let stretch = mul(&transpose(&r), f);
+    let volume_change = (0..3).map(|a| stretch[a][a] - 1.0).sum::<f32>();
+    std::array::from_fn(|a| std::array::from_fn(|b| 2.0 * mu * (f[a][b] - r[a][b]) + lambda * volume_change * r[a][b]))
+}
+
+/// Eigenvalues and eigenvectors (as columns) of a symmetric matrix by
+/// cyclic Jacobi rotations.
+fn symmetric_eigen(m: &Matrix3) -> ([f32; 3], Matrix3) {
+    let mut a = *m;
+    let mut v = IDENTITY;
+    for _ in 0..JACOBI_SWEEPS {
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-12 {
+                continue;
+            }
+            let theta = 0.5 * (a[q][q] - a[p][p]) / a[p][q];
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+            let mut rotation = IDENTITY;
+            rotation[p][p] = c;
+            rotation[q][q] = c;
+            rotation[p][q] = s;
+            rotation[q][p] = -s;
+            a = mul(&transpose(&rotation), &mul(&a, &rotation));
+            v = mul(&v, &rotation);
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+/// Monaghan (2000) artificial stress `R = -epsilon sigma / rho^2` for the
+/// tensile principal components of the Cauchy stress; compressive ones get
+/// none.
+pub fn artificial_stress(cauchy: &Matrix3, rho: f32, epsilon: f32) -> Matrix3 {
+    let (principal, axes) = symmetric_eigen(cauchy);
+    let scaled = principal.map(|s| if s > 0.0 { -epsilon * s / (rho * rho) } else { 0.0 });
+    std::array::from_fn(|a| std::array::from_fn(|b| (0..3).map(|k| axes[a][k] * scaled[k] * axes[b][k]).sum()))
+}
+
+/// Undeformed state of the solid particles for the total Lagrangian
+/// formulation: pairs keep the volumes and kernel gradients of their
+/// reference positions, so the kernel never sees the stretched
+/// configuration that causes the tensile instability in tension.
+#[derive(Debug, Clone, Default)]
+pub struct ReferenceConfiguration {
+    /// Reference neighbors `j > i` of the same solid material
+    pub neighbors: Vec<Vec<usize>>,
+    /// Whether the particle has any reference neighbor
+    pub bonded: Vec<bool>,
+    pub volume: Vec<f32>,
+    /// Corrected reference kernel gradients `(L_i grad W_ij, L_j grad W_ji)`
+    /// for each entry of `neighbors`
+    pub gradients: Vec<Vec<([f32; 3], [f32; 3])>>,
+}
+
+impl ReferenceConfiguration {
+    pub fn new(
+        position: [&[f32]; 3],
+        material: &[usize],
+        materials: &[Material],
+        smoothing_radius: f32,
+        dim: usize,
+    ) -> Self {
+        let n = material.len();
+        let solid = |i: usize| materials[material[i]].is_solid();
+        let mut neighbors = vec![Vec::new(); n];
+        if (0..n).any(solid) {
+            KdTree::default().find_neighbors(position, &vec![smoothing_radius; n], &mut neighbors);
+        }
+        for (i, list) in neighbors.iter_mut().enumerate() {
+            list.retain(|&j| solid(i) && material[i] == material[j]);
+        }
+
+        let mut bonded = vec![false; n];
+        for (i, list) in neighbors.iter().enumerate() {
+            for &j in list {
+                bonded[i] = true;
+                bonded[j] = true;
+            }
+        }
+        let volume: Vec<f32> = material
+            .iter()
+            .map(|&k| materials[k].mass / materials[k].rest_density)
+            .collect();
+
+        // grad_i W_ij; grad_j W_ji is its negative
+        let inv_h = 1.0 / smoothing_radius as f64;
+        let raw: Vec<Vec<[f32; 3]>> = neighbors
+            .iter()
+            .enumerate()
+            .map(|(i, list)| {
+                list.iter()
+                    .map(|&j| {
+                        let dr: [f32; 3] = std::array::from_fn(|a| position[a][i] - position[a][j]);
+                        let r = dr.iter().map(|c| c * c).sum::<f32>().sqrt();
+                        let scale = d_kernel_nd(r as f64, inv_h, dim) as f32 / r;
+                        dr.map(|c| c * scale)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // B_i = sum_j V_j (X_j - X_i) (x) grad W_ij, with the unused
+        // dimensions set to identity; L_i = B_i^-T makes the gradients of
+        // linear fields exact
+        let mut b = vec![unit_block(dim); n];
+        for (i, list) in neighbors.iter().enumerate() {
+            for (&j, g) in list.iter().zip(&raw[i]) {
+                for a in 0..dim {
+                    let dx = position[a][j] - position[a][i];
+                    for c in 0..dim {
+                        b[i][a][c] += volume[j] * dx * g[c];
+                        b[j][a][c] += volume[i] * dx * g[c];
+                    }
+                }
+            }
+        }
+        let correction: Vec<Matrix3> = b
+            .iter()
+            .zip(&bonded)
+            .map(|(b, &bonded)| if bonded { inverse(b).map(|inv| transpose(&inv)).unwrap_or(IDENTITY) } else { IDENTITY })
+            .collect();
+
+        let gradients = neighbors
+            .iter()
+            .enumerate()
+            .map(|(i, list)| {
+                list.iter()
+                    .zip(&raw[i])
+                    .map(|(&j, &g)| (mul_vector(&correction[i], g), mul_vector(&correction[j], g.map(|c| -c))))
+                    .collect()
+            })
+            .collect();
+
+        ReferenceConfiguration { neighbors, bonded, volume, gradients }
+    }
+}
+
+/// Identity outside the first `dim` dimensions and zero inside them.
+pub(crate) fn unit_block(dim: usize) -> Matrix3 {
+    std::array::from_fn(|a| std::array::from_fn(|c| if a == c && a >= dim { 1.0 } else { 0.0 }))
+}
+
+/// Deformation gradient `F_i = sum_j V_j (x_j - x_i) (x) L_i grad W_ij`
+/// relative to the reference configuration; unbonded particles keep the
+/// identity.
+pub fn deformation_gradients(
+    reference: &ReferenceConfiguration,
+    position: [&[f32]; 3],
+    dim: usize,
+    deformation_gradient: &mut [Matrix3],
+) {
+    for (f, &bonded) in deformation_gradient.iter_mut().zip(&reference.bonded) {
+        *f = if bonded { unit_block(dim) } else { IDENTITY };
+    }
+
+    for (i, list) in reference.neighbors.iter().enumerate() {
+        for (&j, (g_ij, g_ji)) in list.iter().zip(&reference.gradients[i]) {
+            for a in 0..dim {
+                let dx = position[a][j] - position[a][i];
+                for c in 0..dim {
+                    deformation_gradient[i][a][c] += reference.volume[j] * dx * g_ij[c];
+                    deformation_gradient[j][a][c] -= reference.volume[i] * dx * g_ji[c];
+                }
+            }
+        }
+    }
+}
+
+/// Borrowed per-particle fields read by the elastic forces.
+pub struct ElasticParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub deformation_gradient: &'a [Matrix3],
+    pub material: &'a [usize],
+}
+
+/// Adds the total Lagrangian elastic accelerations
+/// `f_i = sum_j V_i V_j (P_i g_ij - P_j g_ji)` between particles of the same
+/// solid, plus Monaghan's artificial stress in the current configuration
+/// against particle clumping under tension.
+pub fn accumulate_elastic_forces(
+    reference: &ReferenceConfiguration,
+    particles: &ElasticParticles,
+    materials: &[Material],
+    smoothing_radius: f32,
+    dim: usize,
+    accel: [&mut [f32]; 3],
+) {
+    let mut accel = accel;
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let n = particles.material.len();
+
+    let mut stress = vec![[[0.0; 3]; 3]; n];
+    let mut correction = vec![[[0.0; 3]; 3]; n];
+    for i in (0..n).filter(|&i| reference.bonded[i]) {
+        let material = &materials[particles.material[i]];
+        let f = &particles.deformation_gradient[i];
+        stress[i] = corotated_stress(f, material.elasticity.lame());
+
+        let jacobian = determinant(f);
+        if material.elasticity.artificial_stress > 0.0 && jacobian > 0.0 {
+            let cauchy = mul(&stress[i], &transpose(f)).map(|row| row.map(|s| s / jacobian));
+            let rho = material.rest_density / jacobian;
+            correction[i] = artificial_stress(&cauchy, rho, material.elasticity.artificial_stress);
+        }
+    }
+
+    for (i, list) in reference.neighbors.iter().enumerate() {
+        let material = &materials[particles.material[i]];
+        // Kernel at the reference spacing normalizes the artificial stress
+        let spacing = reference.volume[i].powf(1.0 / dim as f32);
+        let w_spacing = kernel_nd(spacing as f64, inv_h, dim) as f32;
+
+        for (&j, (g_ij, g_ji)) in list.iter().zip(&reference.gradients[i]) {
+            // Force on i; j, of the same material, feels the opposite
+            let traction_i = mul_vector(&stress[i], *g_ij);
+            let traction_j = mul_vector(&stress[j], *g_ji);
+            let mut force: [f32; 3] = std::array::from_fn(|a| {
+                reference.volume[i] * reference.volume[j] * (traction_i[a] - traction_j[a])
+            });
+
+            let dr: [f32; 3] = std::array::from_fn(|a| particles.position[a][i] - particles.position[a][j]);
+            let r = dr.iter().map(|c| c * c).sum::<f32>().sqrt();
+            if r > 0.0 && r < smoothing_radius && w_spacing > 0.0 {
+                let ratio = (kernel_nd(r as f64, inv_h, dim) as f32 / w_spacing).powi(4);
+                let grad = dr.map(|c| c * d_kernel_nd(r as f64, inv_h, dim) as f32 / r);
+                let combined: Matrix3 = std::array::from_fn(|a| std::array::from_fn(|b| correction[i][a][b] + correction[j][a][b]));
+                let repulsion = mul_vector(&combined, grad);
+                for (f, repulsion) in force.iter_mut().zip(repulsion) {
+                    *f += material.mass * material.mass * ratio * repulsion;
+                }
+            }
+
+            for (a, f) in accel.iter_mut().zip(force) {
+                a[i] += f / material.mass;
+                a[j] -= f / material.mass;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::MaterialKind;
+    use crate::test_support::water;
+
+    const SIDE: usize = 12;
+    const SPACING: f32 = 0.1;
+    const SMOOTHING_RADIUS: f32 = 3.0 * SPACING;
+
+    fn jelly(artificial_stress: f32) -> Material {
+        Material {
+            name: "jelly",
+            kind: MaterialKind::Solid,
+            mass: SPACING * SPACING,
+            elasticity: Elasticity { youngs_modulus: 100.0, poisson_ratio: 0.3, artificial_stress },
+            ..water()
+        }
+    }
+
+    /// Square block of solid at unit density in 2D.
+    struct Block {
+        position: [Vec<f32>; 3],
+        material: Vec<usize>,
+        reference: ReferenceConfiguration,
+    }
+
+    impl Block {
+        fn new(materials: &[Material]) -> Self {
+            let mut position = [Vec::new(), Vec::new(), Vec::new()];
+            for i in 0..SIDE {
+                for j in 0..SIDE {
+                    position[0].push(i as f32 * SPACING);
+                    position[1].push(j as f32 * SPACING);
+                    position[2].push(0.0);
+                }
+            }
+            let material = vec![0; SIDE * SIDE];
+            let reference = ReferenceConfiguration::new(
+                [&position[0], &position[1], &position[2]],
+                &material,
+                materials,
+                SMOOTHING_RADIUS,
+                2,
+            );
+            Block { position, material, reference }
+        }
+
+        /// Moves every particle to `map` of its reference position.
+        fn deform(&mut self, map: impl Fn([f32; 2]) -> [f32; 2]) {
+            for i in 0..self.material.len() {
+                let [x, y] = map([self.position[0][i], self.position[1][i]]);
+                self.position[0][i] = x;
+                self.position[1][i] = y;
+            }
+        }
+
+        fn deformation_gradients(&self) -> Vec<Matrix3> {
+            let mut f = vec![IDENTITY; self.material.len()];
+            let position = [&self.position[0][..], &self.position[1][..], &self.position[2][..]];
+            deformation_gradients(&self.reference, position, 2, &mut f);
+            f
+        }
+
+        fn accelerations(&self, materials: &[Material]) -> [Vec<f32>; 3] {
+            let n = self.material.len();
+            let f = self.deformation_gradients();
+            let particles = ElasticParticles {
+                position: [&self.position[0], &self.position[1], &self.position[2]],
+                deformation_gradient: &f,
+                material: &self.material,
+            };
+            let mut accel = [vec![0.0; n], vec![0.0; n], vec![0.0; n]];
+            let [ax, ay, az] = &mut accel;
+            accumulate_elastic_forces(&self.reference, &particles, materials, SMOOTHING_RADIUS, 2, [ax, ay, az]);
+            accel
+        }
+    }
+
+    fn assert_close(a: &Matrix3, b: &Matrix3, tolerance: f32) {
+        for r in 0..3 {
+            for c in 0..3 {
+                assert!((a[r][c] - b[r][c]).abs() < tolerance, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn affine_deformation_is_reproduced_up_to_the_surface() {
+        let materials = [jelly(0.0)];
+        let mut block = Block::new(&materials);
+        let a = [[1.1, 0.2, 0.0], [-0.05, 0.9, 0.0], [0.0, 0.0, 1.0]];
+        block.deform(|[x, y]| [a[0][0] * x + a[0][1] * y + 0.3, a[1][0] * x + a[1][1] * y - 0.2]);
+
+        for f in block.deformation_gradients() {
+            assert_close(&f, &a, 1e-3);
+        }
+    }
+
+    #[test]
+    fn corotated_stress_ignores_rotation_and_linearizes() {
+        let lame = jelly(0.0).elasticity.lame();
+        let (sin, cos) = 0.6_f32.sin_cos();
+        let rotated = [[cos, -sin, 0.0], [sin, cos, 0.0], [0.0, 0.0, 1.0]];
+        assert_close(&corotated_stress(&rotated, lame), &[[0.0; 3]; 3], 1e-3);
+
+        // Small symmetric strain gives Hooke's law in plane strain
+        let strain = [[1e-3, 4e-4, 0.0], [4e-4, -2e-3, 0.0], [0.0, 0.0, 0.0]];
+        let f: Matrix3 = std::array::from_fn(|r| std::array::from_fn(|c| IDENTITY[r][c] + strain[r][c]));
+        let (lambda, mu) = lame;
+        let trace = strain[0][0] + strain[1][1];
+        let hooke: Matrix3 = std::array::from_fn(|r| std::array::from_fn(|c| lambda * trace * IDENTITY[r][c] + 2.0 * mu * strain[r][c]));
+        assert_close(&corotated_stress(&f, lame), &hooke, 1e-3 * lame.1);
+    }
+
+    #[test]
+    fn rigid_motion_exerts_no_force() {
+        let materials = [jelly(0.3)];
+        let mut block = Block::new(&materials);
+        let (sin, cos) = 0.5_f32.sin_cos();
+        block.deform(|[x, y]| [cos * x - sin * y + 1.0, sin * x + cos * y]);
+
+        let accel = block.accelerations(&materials);
+        for a in accel.iter().flatten() {
+            assert!(a.abs() < 1e-2, "acceleration {}", a);
+        }
+    }
+
+    #[test]
+    fn stretched_block_pulls_back_and_conserves_momentum() {
+        let materials = [jelly(0.3)];
+        let mut block = Block::new(&materials);
+        block.deform(|[x, y]| [1.05 * x + 0.02 * y * y, y]);
+
+        let accel = block.accelerations(&materials);
+        let total: Vec<f32> = accel.iter().map(|a| a.iter().sum()).collect();
+        assert!(total.iter().all(|t| t.abs() < 1e-2), "net force {:?}", total);
+
+        // Particles on the right edge are drawn back toward the block
+        let right_edge = (SIDE - 1) * SIDE..SIDE * SIDE;
+        assert!(accel[0][right_edge].iter().all(|&a| a < 0.0));
+    }
+
+    #[test]
+    fn artificial_stress_acts_only_in_tension() {
+        let compression = [[-2.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 0.0]];
+        assert_close(&artificial_stress(&compression, 1.0, 0.3), &[[0.0; 3]; 3], 1e-6);
+
+        // Uniaxial tension along the diagonal is countered along the diagonal
+        let tension = [[1.0, 1.0, 0.0], [1.0, 1.0, 0.0], [0.0, 0.0, 0.0]];
+        let expected = [[-0.3, -0.3, 0.0], [-0.3, -0.3, 0.0], [0.0, 0.0, 0.0]];
+        assert_close(&artificial_stress(&tension, 1.0, 0.3), &expected, 1e-5);
+    }
+}
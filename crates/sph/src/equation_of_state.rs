@@ -0,0 +1,181 @@
+use crate::gas_dynamics::{ideal_gas_pressure, ideal_gas_sound_speed};
+
+/// Closes the SPH equations by relating pressure to density and specific
+/// internal energy. Liquid models ignore `u`.
+pub trait EquationOfState {
+    fn pressure(&self, rho: f32, u: f32) -> f32;
+    fn sound_speed(&self, rho: f32, u: f32) -> f32;
+}
+
+/// Tait (Cole) equation for weakly compressible liquids,
+/// `p = B ((rho / rho0)^gamma - 1) + p_b` with `B = rho0 c0^2 / gamma`.
+/// A positive background pressure `p_b` keeps the pressure from going
+/// negative and so suppresses the tensile instability.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tait {
+    pub reference_density: f32,
+    pub sound_speed: f32,
+    pub gamma: f32,
+    pub background_pressure: f32,
+}
+
+impl EquationOfState for Tait {
+    fn pressure(&self, rho: f32, _u: f32) -> f32 {
+        let b = self.reference_density * self.sound_speed * self.sound_speed / self.gamma;
+        b * ((rho / self.reference_density).powf(self.gamma) - 1.0) + self.background_pressure
+    }
+
+    fn sound_speed(&self, rho: f32, _u: f32) -> f32 {
+        self.sound_speed * (rho / self.reference_density).powf(0.5 * (self.gamma - 1.0))
+    }
+}
+
+/// Cole equation with `gamma = 1`, `p = c0^2 (rho - rho0) + p_b`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Linear {
+    pub reference_density: f32,
+    pub sound_speed: f32,
+    pub background_pressure: f32,
+}
+
+impl EquationOfState for Linear {
+    fn pressure(&self, rho: f32, _u: f32) -> f32 {
+        self.sound_speed * self.sound_speed * (rho - self.reference_density) + self.background_pressure
+    }
+
+    fn sound_speed(&self, _rho: f32, _u: f32) -> f32 {
+        self.sound_speed
+    }
+}
+
+/// Ideal gas, `p = (gamma - 1) rho u`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdealGas {
+    pub gamma: f32,
+}
+
+impl EquationOfState for IdealGas {
+    fn pressure(&self, rho: f32, u: f32) -> f32 {
+        ideal_gas_pressure(rho, u, self.gamma)
+    }
+
+    fn sound_speed(&self, rho: f32, u: f32) -> f32 {
+        ideal_gas_sound_speed(rho, self.pressure(rho, u), self.gamma)
+    }
+}
+
+/// Stiffened gas, `p = (gamma - 1) rho u - gamma p_inf`, used for water and
+/// other condensed matter under strong compression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StiffenedGas {
+    pub gamma: f32,
+    pub stiffness: f32,
+}
+
+impl EquationOfState for StiffenedGas {
+    fn pressure(&self, rho: f32, u: f32) -> f32 {
+        ideal_gas_pressure(rho, u, self.gamma) - self.gamma * self.stiffness
+    }
+
+    fn sound_speed(&self, rho: f32, u: f32) -> f32 {
+        ideal_gas_sound_speed(rho, self.pressure(rho, u) + self.stiffness, self.gamma)
+    }
+}
+
+/// Which equation of state the configuration selects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EquationOfStateKind {
+    Tait,
+    Linear,
+    IdealGas,
+    StiffenedGas,
+}
+
+/// One of the equations of state above, stored by value so materials can
+/// carry their own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EquationOfStateModel {
+    Tait(Tait),
+    Linear(Linear),
+    IdealGas(IdealGas),
+    StiffenedGas(StiffenedGas),
+}
+
+impl EquationOfState for EquationOfStateModel {
+    fn pressure(&self, rho: f32, u: f32) -> f32 {
+        match self {
+            EquationOfStateModel::Tait(eos) => eos.pressure(rho, u),
+            EquationOfStateModel::Linear(eos) => eos.pressure(rho, u),
+            EquationOfStateModel::IdealGas(eos) => eos.pressure(rho, u),
+            EquationOfStateModel::StiffenedGas(eos) => eos.pressure(rho, u),
+        }
+    }
+
+    fn sound_speed(&self, rho: f32, u: f32) -> f32 {
+        match self {
+            EquationOfStateModel::Tait(eos) => eos.sound_speed(rho, u),
+            EquationOfStateModel::Linear(eos) => eos.sound_speed(rho, u),
+            EquationOfStateModel::IdealGas(eos) => eos.sound_speed(rho, u),
+            EquationOfStateModel::StiffenedGas(eos) => eos.sound_speed(rho, u),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAIT: Tait = Tait { reference_density: 1000.0, sound_speed: 20.0, gamma: 7.0, background_pressure: 0.0 };
+    const LINEAR: Linear = Linear { reference_density: 1000.0, sound_speed: 20.0, background_pressure: 0.0 };
+
+    fn pressure_slope(eos: &impl EquationOfState, rho: f32) -> f32 {
+        let d_rho = 1e-2 * rho;
+        (eos.pressure(rho + d_rho, 0.0) - eos.pressure(rho - d_rho, 0.0)) / (2.0 * d_rho)
+    }
+
+    #[test]
+    fn liquids_vanish_at_rest_density_and_agree_to_first_order() {
+        assert_eq!(TAIT.pressure(1000.0, 0.0), 0.0);
+        assert_eq!(LINEAR.pressure(1000.0, 0.0), 0.0);
+
+        let tait = TAIT.pressure(1001.0, 0.0);
+        let linear = LINEAR.pressure(1001.0, 0.0);
+        assert!((tait - linear).abs() < 0.01 * linear, "tait {} linear {}", tait, linear);
+    }
+
+    #[test]
+    fn liquid_sound_speed_matches_pressure_slope() {
+        for rho in [950.0, 1000.0, 1030.0] {
+            for eos in [EquationOfStateModel::Tait(TAIT), EquationOfStateModel::Linear(LINEAR)] {
+                let c = eos.sound_speed(rho, 0.0);
+                let slope = pressure_slope(&eos, rho);
+                assert!((c * c - slope).abs() < 1e-3 * slope, "{:?} at {}: c^2 {} dp/drho {}", eos, rho, c * c, slope);
+            }
+        }
+    }
+
+    #[test]
+    fn background_pressure_shifts_liquid_pressure() {
+        let shifted = Tait { background_pressure: 50.0, ..TAIT };
+        for rho in [990.0, 1000.0, 1010.0] {
+            let shift = shifted.pressure(rho, 0.0) - TAIT.pressure(rho, 0.0);
+            assert!((shift - 50.0).abs() < 0.1, "shift {} at {}", shift, rho);
+        }
+        assert!(TAIT.pressure(999.9, 0.0) < 0.0 && shifted.pressure(999.9, 0.0) > 0.0);
+    }
+
+    #[test]
+    fn stiffened_gas_reduces_to_ideal_gas() {
+        let ideal = IdealGas { gamma: 1.4 };
+        let unstiffened = StiffenedGas { gamma: 1.4, stiffness: 0.0 };
+        assert_eq!(ideal.pressure(1.2, 2.5), unstiffened.pressure(1.2, 2.5));
+        assert_eq!(ideal.sound_speed(1.2, 2.5), unstiffened.sound_speed(1.2, 2.5));
+
+        // Water-like stiffening: c^2 = gamma (p + p_inf) / rho
+        let water = StiffenedGas { gamma: 4.4, stiffness: 6.0e8 };
+        let (rho, u) = (1000.0, 1.0e6);
+        let c = water.sound_speed(rho, u);
+        let expected = 4.4 * (water.pressure(rho, u) + 6.0e8) / rho;
+        assert!((c * c - expected).abs() < 1e-4 * expected);
+    }
+}
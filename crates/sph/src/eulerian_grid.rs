@@ -0,0 +1,199 @@
+use crate::constants::{GLOBALS, N};
+use crate::kernel::kernel_nd;
+use crate::material::Material;
+use crate::spatial_hash::HashedGrid;
+use crate::state::State;
+
+/// Particle quantity that can be sampled onto a grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridField {
+    Density,
+    Pressure,
+    VelocityX,
+    VelocityY,
+    VelocityZ,
+}
+
+/// Uniform grid of samples taken at the cell centers, with x varying
+/// fastest in `values`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldGrid {
+    pub min: [f32; 3],
+    pub cell_length: [f32; 3],
+    pub resolution: [usize; 3],
+    pub values: Vec<f32>,
+}
+
+impl FieldGrid {
+    /// Grid of `resolution` cells per axis spanning `min` to `max`, with all
+    /// values zero.
+    pub fn new(min: [f32; 3], max: [f32; 3], resolution: [usize; 3]) -> Self {
+        let cell_length = std::array::from_fn(|a| (max[a] - min[a]) / resolution[a].max(1) as f32);
+        let values = vec![0.0; resolution.iter().product()];
+        FieldGrid { min, cell_length, resolution, values }
+    }
+
+    pub fn index(&self, cell: [usize; 3]) -> usize {
+        cell[0] + self.resolution[0] * (cell[1] + self.resolution[1] * cell[2])
+    }
+
+    /// Center of the cell at flat index `index`.
+    pub fn center(&self, index: usize) -> [f32; 3] {
+        let cell = [
+            index % self.resolution[0],
+            index / self.resolution[0] % self.resolution[1],
+            index / (self.resolution[0] * self.resolution[1]),
+        ];
+        std::array::from_fn(|a| self.min[a] + (cell[a] as f32 + 0.5) * self.cell_length[a])
+    }
+}
+
+/// Borrowed per-particle fields read when sampling onto a grid.
+pub struct GridParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// Shepard-normalized SPH interpolation of `field` at every cell center,
+/// `f(x) = sum_j V_j f_j W(x - x_j) / sum_j V_j W(x - x_j)`, over the
+/// particles `index` finds within the smoothing radius. Normalizing keeps
+/// uniform fields uniform up to the free surface; cells no particle reaches
+/// are zero.
+pub fn sample_grid(
+    particles: &GridParticles,
+    materials: &[Material],
+    index: &HashedGrid,
+    smoothing_radius: f32,
+    dim: usize,
+    field: &[f32],
+    grid: &mut FieldGrid,
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+
+    for k in 0..grid.values.len() {
+        let point = grid.center(k);
+        let mut weight = 0.0;
+        let mut sum = 0.0;
+        for j in index.query_radius(particles.position, point, smoothing_radius) {
+            let material = &materials[particles.material[j]];
+            if !material.interacts() || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [point[0] - x[j], point[1] - y[j], point[2] - z[j]];
+            let d = (dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2]).sqrt();
+            let w = material.mass / rho[j] * kernel_nd(d as f64, inv_h, dim) as f32;
+            weight += w;
+            sum += w * field[j];
+        }
+        grid.values[k] = if weight > 0.0 { sum / weight } else { 0.0 };
+    }
+}
+
+/// Grid of `resolution` cells per axis covering the simulation box; in 2D it
+/// is a single layer at `z = 0`.
+pub(crate) fn box_grid(resolution: usize) -> FieldGrid {
+    let (box_min, box_max) = (GLOBALS.box_min as f32, GLOBALS.box_max as f32);
+    if GLOBALS.dim == 3 {
+        FieldGrid::new([box_min; 3], [box_max; 3], [resolution; 3])
+    } else {
+        FieldGrid::new([box_min, box_min, 0.0], [box_max, box_max, 0.0], [resolution, resolution, 1])
+    }
+}
+
+/// Index of the current particle positions with cells of one smoothing
+/// radius.
+pub(crate) fn particle_index(state: &State) -> HashedGrid {
+    let mut index = HashedGrid::new(GLOBALS.smoothing_radius as f32, (2 * N).next_power_of_two());
+    index.populate(&state.x, &state.y, &state.z, N);
+    index
+}
+
+/// Samples `field` onto a grid of `resolution` cells per axis covering the
+/// simulation box; in 2D the grid is a single layer at `z = 0`.
+pub fn interpolate_to_grid(state: &State, resolution: usize, field: GridField) -> FieldGrid {
+    let smoothing_radius = GLOBALS.smoothing_radius as f32;
+    let index = particle_index(state);
+    let mut grid = box_grid(resolution);
+
+    let values: &[f32] = match field {
+        GridField::Density => &state.rho,
+        GridField::Pressure => &state.p,
+        GridField::VelocityX => &state.vx,
+        GridField::VelocityY => &state.vy,
+        GridField::VelocityZ => &state.vz,
+    };
+    let particles = GridParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        material: &state.material,
+    };
+    sample_grid(&particles, state.materials.as_slice(), &index, smoothing_radius, GLOBALS.dim, values, &mut grid);
+    grid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{square_lattice, water, SMOOTHING_RADIUS, SPACING};
+
+    const SIDE: usize = 16;
+
+    /// Samples `field` of a square block of water on `[0, 1.5]^2` onto a
+    /// 20 x 20 grid over `[-0.5, 2]^2`.
+    fn sample_block(field: impl Fn(f32, f32) -> f32) -> FieldGrid {
+        let position = square_lattice(SIDE);
+        let n = SIDE * SIDE;
+        let mut index = HashedGrid::new(SMOOTHING_RADIUS, 64);
+        index.populate(&position[0], &position[1], &position[2], n);
+
+        let values: Vec<f32> = (0..n).map(|i| field(position[0][i], position[1][i])).collect();
+        let particles = GridParticles {
+            position: [&position[0], &position[1], &position[2]],
+            rho: &vec![1.0; n],
+            material: &vec![0; n],
+        };
+        let mut grid = FieldGrid::new([-0.5, -0.5, 0.0], [2.0, 2.0, 0.0], [20, 20, 1]);
+        sample_grid(&particles, &[water()], &index, SMOOTHING_RADIUS, 2, &values, &mut grid);
+        grid
+    }
+
+    #[test]
+    fn cell_centers() {
+        let grid = FieldGrid::new([-1.0, 0.0, 0.0], [1.0, 2.0, 0.0], [4, 2, 1]);
+        assert_eq!(grid.values.len(), 8);
+        assert_eq!(grid.center(0), [-0.75, 0.5, 0.0]);
+        assert_eq!(grid.center(grid.index([3, 1, 0])), [0.75, 1.5, 0.0]);
+    }
+
+    #[test]
+    fn uniform_field_stays_uniform_up_to_the_edge() {
+        let grid = sample_block(|_, _| 2.5);
+        for (k, value) in grid.values.iter().enumerate() {
+            // Distance from the cell center to the block of particles
+            let [x, y, _] = grid.center(k);
+            let gap = [x, y].map(|c| (-c).max(c - 1.5).max(0.0));
+            let distance = (gap[0] * gap[0] + gap[1] * gap[1]).sqrt();
+            if distance < SMOOTHING_RADIUS - SPACING {
+                assert!((value - 2.5).abs() < 1e-5, "cell {k} at {x} {y}: {value}");
+            } else if distance >= SMOOTHING_RADIUS {
+                assert_eq!(*value, 0.0, "cell {k} at {x} {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn linear_field_inside_the_block() {
+        let grid = sample_block(|x, y| 1.0 + 0.5 * x - y);
+        for (k, value) in grid.values.iter().enumerate() {
+            let [x, y, _] = grid.center(k);
+            if [x, y].iter().all(|&c| c > SMOOTHING_RADIUS && c < 1.5 - SMOOTHING_RADIUS) {
+                let expected = 1.0 + 0.5 * x - y;
+                assert!((value - expected).abs() < 1e-3, "cell {k} at {x} {y}: {value} {expected}");
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use crate::material::Material;
+use crate::operators::{divergence, gradient, Formulation, Neighborhood, OperatorParticles};
+
+// How far the position divergence may drop below `dim` before a particle
+// counts as lying on the surface
+const SURFACE_DIVERGENCE_MARGIN: f32 = 0.5;
+
+/// Whether a position divergence `div r_i` marks a particle whose kernel
+/// support is cut off by a free surface.
+fn is_surface_divergence(divergence: f32, dim: usize) -> bool {
+    divergence < dim as f32 - SURFACE_DIVERGENCE_MARGIN
+}
+
+/// Borrowed per-particle fields read by the free-surface detection.
+pub struct FreeSurfaceParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// Flags the mobile particles on a free surface and gives their outward unit
+/// normals. A full kernel support has the position divergence
+/// `div r_i = sum_j V_j (x_j - x_i) . grad_i W_ij = dim`; it drops where
+/// neighbors are missing on one side (Lee et al. 2008). The normal points
+/// against the color-field gradient `grad C_i = sum_j V_j grad_i W_ij`, which
+/// leans into the fluid. Particles off the surface get a zero normal.
+pub fn detect_free_surface(
+    particles: &FreeSurfaceParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    surface: &mut [bool],
+    normal: &mut [[f32; 3]],
+) {
+    let rho = particles.rho;
+    let n = rho.len();
+    let operator_particles = OperatorParticles {
+        position: particles.position,
+        rho,
+        material: particles.material,
+    };
+    let neighborhood = Neighborhood { materials, neighbors, smoothing_radius, dim, same_material: false };
+
+    let mut color_gradient = vec![[0.0_f32; 3]; n];
+    gradient(&operator_particles, &neighborhood, Formulation::Summation, &vec![1.0; n], &mut color_gradient);
+    let mut position_divergence = vec![0.0_f32; n];
+    divergence(&operator_particles, &neighborhood, Formulation::Difference, particles.position, &mut position_divergence);
+
+    for i in 0..n {
+        surface[i] = false;
+        normal[i] = [0.0; 3];
+        let material = &materials[particles.material[i]];
+        if !material.is_mobile() || rho[i] <= 0.0 || !is_surface_divergence(position_divergence[i], dim) {
+            continue;
+        }
+
+        surface[i] = true;
+        let color = color_gradient[i];
+        let norm = color.iter().map(|g| g * g).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            normal[i] = color.map(|g| -g / norm);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{half_neighbor_lists, square_lattice, water, SMOOTHING_RADIUS};
+
+    const SIDE: usize = 12;
+
+    /// Surface flags and normals of a square block of water.
+    fn detect_block() -> (Vec<bool>, Vec<[f32; 3]>) {
+        let position = square_lattice(SIDE);
+        let n = SIDE * SIDE;
+        let neighbors = half_neighbor_lists(&position);
+        let particles = FreeSurfaceParticles {
+            position: [&position[0], &position[1], &position[2]],
+            rho: &vec![1.0; n],
+            material: &vec![0; n],
+        };
+        let mut surface = vec![false; n];
+        let mut normal = vec![[1.0; 3]; n];
+        detect_free_surface(&particles, &[water()], &neighbors, SMOOTHING_RADIUS, 2, &mut surface, &mut normal);
+        (surface, normal)
+    }
+
+    fn index(i: usize, j: usize) -> usize {
+        i * SIDE + j
+    }
+
+    #[test]
+    fn interior_is_not_surface() {
+        let (surface, normal) = detect_block();
+        for i in 3..SIDE - 3 {
+            for j in 3..SIDE - 3 {
+                assert!(!surface[index(i, j)], "{i} {j}");
+                assert_eq!(normal[index(i, j)], [0.0; 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn edges_are_surface_with_outward_normals() {
+        let (surface, normal) = detect_block();
+        for i in 3..SIDE - 3 {
+            for (k, expected) in [(index(i, SIDE - 1), [0.0, 1.0]), (index(i, 0), [0.0, -1.0]), (index(0, i), [-1.0, 0.0])] {
+                assert!(surface[k], "{i}");
+                assert!((normal[k][0] - expected[0]).abs() < 1e-4, "{i}: {:?}", normal[k]);
+                assert!((normal[k][1] - expected[1]).abs() < 1e-4, "{i}: {:?}", normal[k]);
+            }
+        }
+    }
+
+    #[test]
+    fn corner_normal_is_diagonal() {
+        let (surface, normal) = detect_block();
+        let corner = index(SIDE - 1, SIDE - 1);
+        assert!(surface[corner]);
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((normal[corner][0] - diagonal).abs() < 1e-4, "{:?}", normal[corner]);
+        assert!((normal[corner][1] - diagonal).abs() < 1e-4, "{:?}", normal[corner]);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::elasticity::{artificial_stress, Matrix3};
+use crate::kernel::{d_kernel_nd, kernel_nd};
+use crate::material::Material;
+
+// Monaghan artificial viscosity coefficients damping the elastic waves
+const VISCOSITY_ALPHA: f32 = 1.0;
+const VISCOSITY_BETA: f32 = 0.0;
+
+/// Drucker-Prager yield criterion of a granular material.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DruckerPrager {
+    /// Internal friction angle in radians
+    pub friction_angle: f32,
+    pub cohesion: f32,
+}
+
+impl DruckerPrager {
+    /// Plane-strain fit `(alpha, k)` of the criterion
+    /// `alpha I1 + sqrt(J2) <= k` to Mohr-Coulomb (Bui et al. 2008).
+    pub fn coefficients(&self) -> (f32, f32) {
+        let tan = self.friction_angle.tan();
+        let denominator = (9.0 + 12.0 * tan * tan).sqrt();
+        (tan / denominator, 3.0 * self.cohesion / denominator)
+    }
+
+    /// Returns a stress (tension positive) onto the yield surface: tensile
+    /// stress beyond the apex is removed from the hydrostatic part, then the
+    /// deviatoric part is scaled back to the cone.
+    pub fn return_mapping(&self, stress: &Matrix3) -> Matrix3 {
+        let (alpha, k) = self.coefficients();
+        let mut stress = *stress;
+
+        let trace = |s: &Matrix3| s[0][0] + s[1][1] + s[2][2];
+        if alpha > 0.0 && k - alpha * trace(&stress) < 0.0 {
+            let excess = (trace(&stress) - k / alpha) / 3.0;
+            for (a, row) in stress.iter_mut().enumerate() {
+                row[a] -= excess;
+            }
+        }
+
+        let mean = trace(&stress) / 3.0;
+        let mut deviator = stress;
+        for (a, row) in deviator.iter_mut().enumerate() {
+            row[a] -= mean;
+        }
+        let j2 = 0.5 * deviator.iter().flatten().map(|s| s * s).sum::<f32>();
+        let limit = (k - 3.0 * alpha * mean).max(0.0);
+        if j2.sqrt() > limit {
+            let scale = limit / j2.sqrt();
+            for (a, row) in stress.iter_mut().enumerate() {
+                for (b, s) in row.iter_mut().enumerate() {
+                    *s = deviator[a][b] * scale + if a == b { mean } else { 0.0 };
+                }
+            }
+        }
+        stress
+    }
+
+    /// Velocity after an inelastic impact on walls normal to the axes in
+    /// `contact`. The normal part is removed and Coulomb friction with the
+    /// material's friction angle takes `tan(phi)` times that impulse from the
+    /// tangential part, bringing it to rest if that is all it has.
+    pub fn wall_contact(&self, velocity: [f32; 3], contact: [bool; 3]) -> [f32; 3] {
+        let mut tangential = velocity;
+        let mut impact = 0.0;
+        for a in 0..3 {
+            if contact[a] {
+                impact += velocity[a] * velocity[a];
+                tangential[a] = 0.0;
+            }
+        }
+
+        let friction = self.friction_angle.tan() * impact.sqrt();
+        let speed = tangential.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if speed <= friction {
+            return [0.0; 3];
+        }
+        tangential.map(|v| v * (1.0 - friction / speed))
+    }
+}
+
+/// Advances the deviatoric stress of granular particles by one step of the
+/// Jaumann rate of linear elasticity, `s' = 2 mu D' + W s - s W`, adds the
+/// equation-of-state pressure as the hydrostatic part and returns the result
+/// to the yield surface. Taking the pressure from the density rather than
+/// integrating it keeps particles from clumping under compression.
+pub fn update_stresses(
+    materials: &[Material],
+    material: &[usize],
+    pressure: &[f32],
+    velocity_gradient: &[Matrix3],
+    dt: f32,
+    stress: &mut [Matrix3],
+) {
+    for (((sigma, &k), &p), l) in stress.iter_mut().zip(material).zip(pressure).zip(velocity_gradient) {
+        let material = &materials[k];
+        if !material.is_granular() {
+            continue;
+        }
+        let (_, mu) = material.elasticity.lame();
+
+        let strain_rate: Matrix3 = std::array::from_fn(|a| std::array::from_fn(|b| 0.5 * (l[a][b] + l[b][a])));
+        let spin: Matrix3 = std::array::from_fn(|a| std::array::from_fn(|b| 0.5 * (l[a][b] - l[b][a])));
+        let volume_rate = strain_rate[0][0] + strain_rate[1][1] + strain_rate[2][2];
+
+        let mut old = *sigma;
+        let mean = (old[0][0] + old[1][1] + old[2][2]) / 3.0;
+        for (a, row) in old.iter_mut().enumerate() {
+            row[a] -= mean;
+        }
+        for a in 0..3 {
+            for b in 0..3 {
+                let rotation: f32 = (0..3).map(|c| spin[a][c] * old[c][b] - old[a][c] * spin[c][b]).sum();
+                let deviatoric_rate = strain_rate[a][b] - if a == b { volume_rate / 3.0 } else { 0.0 };
+                let hydrostatic = if a == b { -p } else { 0.0 };
+                sigma[a][b] = old[a][b] + dt * (2.0 * mu * deviatoric_rate + rotation) + hydrostatic;
+            }
+        }
+        *sigma = material.plasticity.return_mapping(sigma);
+    }
+}
+
+/// Borrowed per-particle fields read by the granular momentum equation.
+pub struct GranularParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub velocity: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub stress: &'a [Matrix3],
+    pub material: &'a [usize],
+}
+
+/// Adds `dv_i/dt = sum_j m_j (sigma_i / rho_i^2 + sigma_j / rho_j^2 - Pi_ij I
+/// + R_ij f^4) grad_i W_ij` between particles of the same granular material,
+/// with Monaghan's artificial viscosity `Pi` and artificial stress `R`.
+pub fn accumulate_granular_forces(
+    particles: &GranularParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    accel: [&mut [f32]; 3],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let [vx, vy, vz] = particles.velocity;
+    let rho = particles.rho;
+    let stress = particles.stress;
+
+    let correction: Vec<Matrix3> = (0..rho.len())
+        .map(|i| {
+            let material = &materials[particles.material[i]];
+            if material.is_granular() && rho[i] > 0.0 {
+                artificial_stress(&stress[i], rho[i], material.elasticity.artificial_stress)
+            } else {
+                [[0.0; 3]; 3]
+            }
+        })
+        .collect();
+
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        let material = &materials[particles.material[i]];
+        if !material.is_granular() {
+            continue;
+        }
+        let (lambda, mu) = material.elasticity.lame();
+        let sound_speed = ((lambda + 2.0 * mu) / material.rest_density).sqrt();
+        // Kernel at the rest spacing normalizes the artificial stress
+        let spacing = (material.mass / material.rest_density).powf(1.0 / dim as f32);
+        let w_spacing = kernel_nd(spacing as f64, inv_h, dim) as f32;
+
+        for &j in neighbor_list {
+            if particles.material[j] != particles.material[i] || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad = dr.map(|c| c * d_kernel_nd(d as f64, inv_h, dim) as f32 / d);
+
+            // Artificial viscosity between approaching particles
+            let v_dot_r = (vx[i] - vx[j]) * dr[0] + (vy[i] - vy[j]) * dr[1] + (vz[i] - vz[j]) * dr[2];
+            let viscosity = if v_dot_r < 0.0 {
+                let h = 0.5 * smoothing_radius;
+                let mu_ij = h * v_dot_r / (r2 + 0.01 * h * h);
+                (-VISCOSITY_ALPHA * sound_speed * mu_ij + VISCOSITY_BETA * mu_ij * mu_ij) / (0.5 * (rho[i] + rho[j]))
+            } else {
+                0.0
+            };
+
+            let ratio = if w_spacing > 0.0 { (kernel_nd(d as f64, inv_h, dim) as f32 / w_spacing).powi(4) } else { 0.0 };
+            // Kernel truncation at free surfaces and walls underestimates the
+            // density, so it is bounded below by the rest density
+            let rho_i_sq = rho[i].max(material.rest_density).powi(2);
+            let rho_j_sq = rho[j].max(material.rest_density).powi(2);
+
+            // Acceleration of i per unit mass of j; j feels the opposite
+            for a in 0..3 {
+                let mut total = 0.0;
+                for b in 0..3 {
+                    let isotropic = if a == b { viscosity } else { 0.0 };
+                    let tensor = stress[i][a][b] / rho_i_sq + stress[j][a][b] / rho_j_sq - isotropic
+                        + ratio * (correction[i][a][b] + correction[j][a][b]);
+                    total += tensor * grad[b];
+                }
+                accel[a][i] += material.mass * total;
+                accel[a][j] -= material.mass * total;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elasticity::Elasticity;
+    use crate::material::MaterialKind;
+    use crate::test_support::water;
+
+    fn sand() -> Material {
+        Material {
+            name: "sand",
+            kind: MaterialKind::Granular,
+            mass: 0.01,
+            elasticity: Elasticity { youngs_modulus: 100.0, poisson_ratio: 0.3, artificial_stress: 0.0 },
+            plasticity: DruckerPrager { friction_angle: 30f32.to_radians(), cohesion: 0.0 },
+            ..water()
+        }
+    }
+
+    fn invariants(stress: &Matrix3) -> (f32, f32) {
+        let mean = (stress[0][0] + stress[1][1] + stress[2][2]) / 3.0;
+        let j2 = (0..3)
+            .flat_map(|a| (0..3).map(move |b| (a, b)))
+            .map(|(a, b)| stress[a][b] - if a == b { mean } else { 0.0 })
+            .map(|s| 0.5 * s * s)
+            .sum::<f32>();
+        (mean, j2.sqrt())
+    }
+
+    #[test]
+    fn return_mapping_projects_onto_the_cone() {
+        let plasticity = sand().plasticity;
+        let (alpha, k) = plasticity.coefficients();
+
+        // Compressed with little shear: admissible and left alone
+        let inside = [[-10.0, 1.0, 0.0], [1.0, -10.0, 0.0], [0.0, 0.0, -10.0]];
+        assert_eq!(plasticity.return_mapping(&inside), inside);
+
+        // Strong shear keeps its pressure and lands on the yield surface
+        let outside = [[-10.0, 20.0, 0.0], [20.0, -10.0, 0.0], [0.0, 0.0, -10.0]];
+        let (mean, shear) = invariants(&plasticity.return_mapping(&outside));
+        assert!((mean + 10.0).abs() < 1e-4);
+        assert!((shear - (k - 3.0 * alpha * mean)).abs() < 1e-4, "{shear}");
+
+        // Cohesionless sand carries no tension
+        let tension = [[5.0, 1.0, 0.0], [1.0, 2.0, 0.0], [0.0, 0.0, 3.0]];
+        let (mean, shear) = invariants(&plasticity.return_mapping(&tension));
+        assert!(mean.abs() < 1e-5 && shear.abs() < 1e-5, "{mean} {shear}");
+    }
+
+    #[test]
+    fn cohesion_admits_shear_without_pressure() {
+        let plasticity = DruckerPrager { friction_angle: 30f32.to_radians(), cohesion: 2.0 };
+        let (_, k) = plasticity.coefficients();
+        let shear = [[0.0, 0.5 * k, 0.0], [0.5 * k, 0.0, 0.0], [0.0, 0.0, 0.0]];
+        assert_eq!(plasticity.return_mapping(&shear), shear);
+    }
+
+    #[test]
+    fn elastic_shear_step_and_pressure() {
+        let materials = [sand()];
+        let (_, mu) = materials[0].elasticity.lame();
+        let rate = 0.01;
+        let dt = 0.1;
+
+        // Simple shear v_x = rate * y under a pressure of 4
+        let mut gradient = [[0.0; 3]; 3];
+        gradient[0][1] = rate;
+        let mut stress = [[[0.0; 3]; 3]];
+        update_stresses(&materials, &[0], &[4.0], &[gradient], dt, &mut stress);
+
+        let expected_shear = mu * rate * dt;
+        assert!((stress[0][0][1] - expected_shear).abs() < 1e-5, "{:?}", stress[0]);
+        assert_eq!(stress[0][0][1], stress[0][1][0]);
+        for a in 0..3 {
+            assert!((stress[0][a][a] + 4.0).abs() < 1e-5, "{:?}", stress[0]);
+        }
+    }
+
+    #[test]
+    fn pair_forces_conserve_momentum() {
+        let materials = [sand()];
+        let position = [vec![0.0, 0.1, 0.05], vec![0.0, 0.0, 0.08], vec![0.0; 3]];
+        let velocity = [vec![0.0, -0.5, 0.2], vec![0.1, 0.0, -0.3], vec![0.0; 3]];
+        let rho = [1.0, 1.1, 0.9];
+        let stress = [
+            [[-3.0, 0.5, 0.0], [0.5, -2.0, 0.0], [0.0, 0.0, -2.5]],
+            [[-1.0, -0.2, 0.0], [-0.2, -4.0, 0.0], [0.0, 0.0, -2.5]],
+            [[-2.0, 0.0, 0.0], [0.0, -2.0, 0.0], [0.0, 0.0, -2.0]],
+        ];
+        let particles = GranularParticles {
+            position: [&position[0], &position[1], &position[2]],
+            velocity: [&velocity[0], &velocity[1], &velocity[2]],
+            rho: &rho,
+            stress: &stress,
+            material: &[0, 0, 0],
+        };
+        let neighbors = vec![vec![1, 2], vec![2], vec![]];
+        let mut accel = [vec![0.0; 3], vec![0.0; 3], vec![0.0; 3]];
+        let [ax, ay, az] = &mut accel;
+        accumulate_granular_forces(&particles, &materials, &neighbors, 0.3, 2, [ax, ay, az]);
+
+        for component in &accel[..2] {
+            assert!(component.iter().any(|a| a.abs() > 1e-3));
+        }
+        for component in &accel {
+            assert!(component.iter().sum::<f32>().abs() < 1e-4, "{component:?}");
+        }
+    }
+
+    /// Speed after a grain starting at rest has slid for one second on a floor
+    /// tilted by `tilt` degrees, with gravity pressing it onto the floor at
+    /// every step.
+    fn sliding_speed(tilt: f32) -> f32 {
+        let plasticity = sand().plasticity;
+        let gravity = [9.81 * tilt.to_radians().sin(), -9.81 * tilt.to_radians().cos(), 0.0];
+        let dt = 1e-3;
+        let mut velocity = [0.0; 3];
+        for _ in 0..1000 {
+            velocity = std::array::from_fn(|a| velocity[a] + gravity[a] * dt);
+            velocity = plasticity.wall_contact(velocity, [false, true, false]);
+        }
+        velocity[0]
+    }
+
+    #[test]
+    fn grains_rest_on_walls_up_to_the_friction_angle() {
+        // The friction angle of `sand` is 30 degrees
+        assert_eq!(sliding_speed(28.0), 0.0);
+        let speed = sliding_speed(32.0);
+        let expected = 9.81 * (32f32.to_radians().sin() - 30f32.to_radians().tan() * 32f32.to_radians().cos());
+        assert!((speed - expected).abs() < 1e-3 * expected.max(1.0), "{speed} {expected}");
+    }
+
+    #[test]
+    fn wall_contact_stops_the_normal_motion() {
+        let plasticity = sand().plasticity;
+        let velocity = plasticity.wall_contact([3.0, -1.0, 0.0], [false, true, false]);
+        let friction = 30f32.to_radians().tan();
+        assert_eq!(velocity[1], 0.0);
+        assert!((velocity[0] - (3.0 - friction)).abs() < 1e-6, "{velocity:?}");
+        assert_eq!(plasticity.wall_contact([0.2, 1.0, 0.0], [true, true, false]), [0.0; 3]);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::kernel::d_kernel_nd;
+use crate::material::Material;
+
+/// Axis-aligned region whose particles are held at a fixed temperature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThermalWall {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+    pub temperature: f32,
+}
+
+impl ThermalWall {
+    pub fn contains(&self, point: [f32; 3]) -> bool {
+        (0..3).all(|d| point[d] >= self.min[d] && point[d] <= self.max[d])
+    }
+}
+
+/// Borrowed per-particle fields read by the conduction equation.
+pub struct ThermalParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub temperature: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// Adds the rate of change of temperature due to conduction in the form of
+/// Cleary & Monaghan (1999), which keeps the heat flux continuous across
+/// materials of different conductivity and conserves thermal energy.
+pub fn accumulate_heat_conduction(
+    particles: &ThermalParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    dtemperature: &mut [f32],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+    let temperature = particles.temperature;
+    // Keeps the flux finite for nearly coincident particles
+    let eta2 = 1e-4 * smoothing_radius * smoothing_radius;
+
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let material_i = &materials[particles.material[i]];
+            let material_j = &materials[particles.material[j]];
+            let conductivity_sum = material_i.conductivity + material_j.conductivity;
+            if !material_i.interacts() || !material_j.interacts() || conductivity_sum <= 0.0 {
+                continue;
+            }
+            if rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dx = x[i] - x[j];
+            let dy = y[i] - y[j];
+            let dz = z[i] - z[j];
+            let r2 = dx * dx + dy * dy + dz * dz;
+            if r2 >= smoothing_radius * smoothing_radius {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            // r_ij . grad_i W_ij = r dW/dr
+            let r_dot_grad = d * d_kernel_nd(d as f64, inv_h, dim) as f32;
+            let conductivity = 4.0 * material_i.conductivity * material_j.conductivity / conductivity_sum;
+            let flux = conductivity * (temperature[i] - temperature[j]) * r_dot_grad / (rho[i] * rho[j] * (r2 + eta2));
+
+            dtemperature[i] += material_j.mass * flux / material_i.specific_heat;
+            dtemperature[j] -= material_i.mass * flux / material_j.specific_heat;
+        }
+    }
+}
+
+/// Boussinesq buoyancy: the density change with temperature only enters
+/// through the body force, `a = -g beta (T - T0)`.
+pub fn boussinesq_acceleration(gravity: [f32; 3], thermal_expansion: f32, temperature_excess: f32) -> [f32; 3] {
+    gravity.map(|g| -g * thermal_expansion * temperature_excess)
+}
+
+/// Holds the particles inside each wall region at the wall temperature.
+pub fn apply_thermal_walls(walls: &[ThermalWall], position: [&[f32]; 3], temperature: &mut [f32]) {
+    for (i, t) in temperature.iter_mut().enumerate() {
+        let point = [position[0][i], position[1][i], position[2][i]];
+        if let Some(wall) = walls.iter().find(|wall| wall.contains(point)) {
+            *t = wall.temperature;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::water;
+
+    fn material(conductivity: f32, specific_heat: f32, mass: f32) -> Material {
+        Material { mass, conductivity, specific_heat, ..water() }
+    }
+
+    /// Abramowitz & Stegun 7.1.26
+    fn erf(x: f32) -> f32 {
+        let t = 1.0 / (1.0 + 0.327_591_1 * x.abs());
+        let poly = t * (0.254_829_6 + t * (-0.284_496_7 + t * (1.421_413_7 + t * (-1.453_152 + t * 1.061_405_4))));
+        (1.0 - poly * (-x * x).exp()).copysign(x)
+    }
+
+    /// Line of particles at unit density with a temperature step at `x = 0`.
+    struct Rod {
+        x: Vec<f32>,
+        zeros: Vec<f32>,
+        rho: Vec<f32>,
+        temperature: Vec<f32>,
+        material: Vec<usize>,
+        neighbors: Vec<Vec<usize>>,
+    }
+
+    impl Rod {
+        fn new(n: usize, spacing: f32, smoothing_radius: f32, materials: [usize; 2]) -> Self {
+            let x: Vec<f32> = (0..n).map(|i| (i as f32 - 0.5 * (n - 1) as f32) * spacing).collect();
+            let neighbors = (0..n)
+                .map(|i| (i + 1..n).filter(|&j| x[j] - x[i] < smoothing_radius).collect())
+                .collect();
+            Rod {
+                temperature: x.iter().map(|&x| if x < 0.0 { 1.0 } else { 0.0 }).collect(),
+                material: x.iter().map(|&x| materials[(x > 0.0) as usize]).collect(),
+                zeros: vec![0.0; n],
+                rho: vec![1.0; n],
+                x,
+                neighbors,
+            }
+        }
+
+        fn conduct(&mut self, materials: &[Material], smoothing_radius: f32, dt: f32, steps: usize) {
+            for _ in 0..steps {
+                let mut dtemperature = vec![0.0; self.x.len()];
+                let particles = ThermalParticles {
+                    position: [&self.x, &self.zeros, &self.zeros],
+                    rho: &self.rho,
+                    temperature: &self.temperature,
+                    material: &self.material,
+                };
+                accumulate_heat_conduction(&particles, materials, &self.neighbors, smoothing_radius, 1, &mut dtemperature);
+                for (t, dt_dt) in self.temperature.iter_mut().zip(&dtemperature) {
+                    *t += dt * dt_dt;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn temperature_step_diffuses_like_error_function() {
+        let spacing = 0.01;
+        let smoothing_radius = 3.0 * spacing;
+        let diffusivity = 0.5;
+        let materials = [material(diffusivity, 1.0, spacing)];
+        let mut rod = Rod::new(200, spacing, smoothing_radius, [0, 0]);
+
+        let dt = 0.05 * spacing * spacing / diffusivity;
+        let steps = 400;
+        rod.conduct(&materials, smoothing_radius, dt, steps);
+
+        let t = dt * steps as f32;
+        for (x, temperature) in rod.x.iter().zip(&rod.temperature) {
+            if x.abs() < 0.5 {
+                let exact = 0.5 * (1.0 - erf(x / (2.0 * (diffusivity * t).sqrt())));
+                assert!((temperature - exact).abs() < 0.02, "T({}) = {}, expected {}", x, temperature, exact);
+            }
+        }
+    }
+
+    #[test]
+    fn conduction_between_materials_conserves_heat() {
+        let spacing = 0.01;
+        let smoothing_radius = 3.0 * spacing;
+        let materials = [material(0.2, 1.0, spacing), material(2.0, 4.0, spacing)];
+        let mut rod = Rod::new(100, spacing, smoothing_radius, [0, 1]);
+
+        let heat = |rod: &Rod| -> f32 {
+            rod.temperature
+                .iter()
+                .zip(&rod.material)
+                .map(|(t, &k)| materials[k].mass * materials[k].specific_heat * t)
+                .sum()
+        };
+        let initial = heat(&rod);
+        rod.conduct(&materials, smoothing_radius, 1e-5, 200);
+
+        assert!((heat(&rod) - initial).abs() < 1e-4 * initial);
+        // Heat has flowed from the hot into the cold material
+        assert!(rod.temperature[50] > 0.0 && rod.temperature[49] < 1.0);
+    }
+
+    #[test]
+    fn hot_fluid_rises() {
+        let gravity = [0.0, -9.81, 0.0];
+        let hot = boussinesq_acceleration(gravity, 2e-3, 10.0);
+        let cold = boussinesq_acceleration(gravity, 2e-3, -10.0);
+
+        assert!(hot[1] > 0.0 && cold[1] < 0.0);
+        assert!((hot[1] - 9.81 * 2e-2).abs() < 1e-6);
+        assert_eq!(hot[0], 0.0);
+    }
+
+    #[test]
+    fn walls_hold_their_temperature() {
+        let walls = [
+            ThermalWall { min: [-1.0, -1.0, -1.0], max: [1.0, -0.9, 1.0], temperature: 2.0 },
+            ThermalWall { min: [-1.0, 0.9, -1.0], max: [1.0, 1.0, 1.0], temperature: -2.0 },
+        ];
+        let x = [0.0, 0.0, 0.0];
+        let y = [-0.95, 0.0, 0.95];
+        let z = [0.0, 0.0, 0.0];
+        let mut temperature = [0.5, 0.5, 0.5];
+
+        apply_thermal_walls(&walls, [&x, &y, &z], &mut temperature);
+
+        assert_eq!(temperature, [2.0, 0.5, -2.0]);
+    }
+}
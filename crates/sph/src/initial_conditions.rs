@@ -1,6 +1,15 @@
 use crate::state::State;
 use crate::constants::{N, GLOBALS};
-use crate::spatial_hash::compute_grid;
+use crate::spatial_hash::{compute_grid, HashedGrid};
+use crate::verlet::VerletList;
+use crate::material::{Material, MaterialKind, MaterialRegistry};
+use crate::heat::ThermalWall;
+use crate::rheology::ViscosityModel;
+use crate::granular::DruckerPrager;
+use crate::elasticity::{Elasticity, ReferenceConfiguration, IDENTITY};
+use crate::gas_dynamics::FluidModel;
+use crate::continuity::DensityEvolution;
+use crate::equation_of_state::{EquationOfStateKind, EquationOfStateModel, IdealGas, Linear, StiffenedGas, Tait};
 
 pub fn fill_state(state: &mut State) {
     // Initialize grid structure
@@ -9,22 +18,23 @@ pub fn fill_state(state: &mut State) {
         &[GLOBALS.box_min as f32, GLOBALS.box_max as f32], 
         &[0.0_f32, 0.0_f32]
     ];
-    let grid = compute_grid(&extents, GLOBALS.smoothing_radius as f32);
+    let grid = compute_grid(&extents, GLOBALS.neighbor_search_radius() as f32);
     let n_cells = grid.count.iter().product();
     
     // Initialize collections
     state.grid = grid;
+    state.hashed_grid = HashedGrid::new(GLOBALS.neighbor_search_radius() as f32, (2 * N).next_power_of_two());
     state.cell_contents = vec![Vec::new(); n_cells];
     state.point_to_cell = vec![0; N];
     state.neighbors = vec![Vec::new(); N];
+    state.verlet = VerletList::new(GLOBALS.verlet_skin as f32, N);
     
     // Initialize scalar values
-    state.particle_mass = 1.0 / N as f32;
     state.inv_h = 1.0 / GLOBALS.smoothing_radius as f32;
     
-    let reference_density = 2.0 / (GLOBALS.box_max - GLOBALS.box_min).powi(2);
-    state.inv_reference_density = 1.0 / reference_density as f32;
-    state.tait_b = (reference_density * GLOBALS.tait_c * GLOBALS.tait_c / GLOBALS.tait_gamma) as f32;
+    let reference_density = (2.0 / (GLOBALS.box_max - GLOBALS.box_min).powi(2)) as f32;
+    state.materials = materials(reference_density, 1.0 / N as f32);
+    state.thermal_walls = thermal_walls();
     
     // Initialize neighbor offsets
     let mut neighbor_offsets = Vec::new();
@@ -89,8 +99,207 @@ pub fn fill_state(state: &mut State) {
             // Set density and pressure to zero
             state.rho[particle_index] = 0.0;
             state.p[particle_index] = 0.0;
+            state.drho[particle_index] = 0.0;
+
+            // Heavy fluid above the interface seeds a Rayleigh-Taylor instability
+            let heavy = GLOBALS.multiphase && y > GLOBALS.phase_interface_height;
+            state.material[particle_index] = heavy as usize;
+            if GLOBALS.elastic_solid && in_solid_region(x, y) {
+                state.material[particle_index] = state.materials.len() - 1;
+            }
+
+            // Start from the global smoothing length
+            state.h[particle_index] = GLOBALS.smoothing_radius as f32;
+            state.omega[particle_index] = 1.0;
+
+            // Fluid starts in thermal equilibrium
+            state.temperature[particle_index] = GLOBALS.reference_temperature as f32;
+            state.dtemperature[particle_index] = 0.0;
+
+            // Uniform specific internal energy for the gas model
+            state.u[particle_index] = GLOBALS.gas_initial_energy as f32;
+            state.du[particle_index] = 0.0;
+            state.du_[particle_index] = 0.0;
             
             particle_index += 1;
         }
     }
-}
\ No newline at end of file
+
+    // Solids measure their deformation from the initial layout
+    state.reference = ReferenceConfiguration::new(
+        [&state.x, &state.y, &state.z],
+        &state.material,
+        state.materials.as_slice(),
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+    );
+    state.deformation_gradient = [IDENTITY; N];
+
+    // The continuity equation starts from rest rather than from a summation,
+    // which would leave particles at the free surface under tension
+    if GLOBALS.density_evolution == DensityEvolution::Continuity {
+        for i in 0..N {
+            state.rho[i] = state.materials[state.material[i]].rest_density;
+        }
+    }
+}
+
+/// Materials of the scene: the fluid, and for the multiphase scene a heavy
+/// second fluid with a lower sound speed, chosen so both phases share the
+/// same Tait stiffness. The granular scene fills the container with sand
+/// instead, and an elastic solid is registered last.
+fn materials(reference_density: f32, mass: f32) -> MaterialRegistry {
+    let sound_speed = GLOBALS.tait_c as f32;
+    let mut materials = MaterialRegistry::default();
+
+    if GLOBALS.granular {
+        let elasticity = Elasticity {
+            youngs_modulus: GLOBALS.youngs_modulus as f32,
+            poisson_ratio: GLOBALS.poisson_ratio as f32,
+            artificial_stress: GLOBALS.artificial_stress as f32,
+        };
+        // Sand resists compression with its elastic bulk modulus
+        let (lambda, mu) = elasticity.lame();
+        let bulk_sound_speed = ((lambda + 2.0 * mu / 3.0) / reference_density).sqrt();
+        materials.register(Material {
+            name: "sand",
+            kind: MaterialKind::Granular,
+            rest_density: reference_density,
+            mass,
+            viscosity: 0.0,
+            equation_of_state: equation_of_state(reference_density, bulk_sound_speed),
+            color: [0.85, 0.7, 0.4],
+            conductivity: GLOBALS.thermal_conductivity as f32,
+            specific_heat: GLOBALS.specific_heat as f32,
+            thermal_expansion: GLOBALS.thermal_expansion as f32,
+            rheology: ViscosityModel::Newtonian,
+            elasticity,
+            plasticity: DruckerPrager {
+                friction_angle: (GLOBALS.friction_angle as f32).to_radians(),
+                cohesion: GLOBALS.cohesion as f32,
+            },
+        });
+    } else if !GLOBALS.multiphase {
+        materials.register(Material {
+            name: "fluid",
+            kind: MaterialKind::Fluid,
+            rest_density: reference_density,
+            mass,
+            viscosity: GLOBALS.viscosity as f32,
+            equation_of_state: equation_of_state(reference_density, sound_speed),
+            color: [0.2, 0.45, 0.9],
+            conductivity: GLOBALS.thermal_conductivity as f32,
+            specific_heat: GLOBALS.specific_heat as f32,
+            thermal_expansion: GLOBALS.thermal_expansion as f32,
+            rheology: GLOBALS.rheology,
+            elasticity: Elasticity::default(),
+            plasticity: DruckerPrager::default(),
+        });
+    } else {
+        register_phases(&mut materials, reference_density, mass);
+    }
+
+    if GLOBALS.elastic_solid {
+        materials.register(Material {
+            name: "jelly",
+            kind: MaterialKind::Solid,
+            rest_density: reference_density,
+            mass,
+            viscosity: GLOBALS.viscosity as f32,
+            equation_of_state: equation_of_state(reference_density, sound_speed),
+            color: [0.85, 0.25, 0.45],
+            conductivity: GLOBALS.thermal_conductivity as f32,
+            specific_heat: GLOBALS.specific_heat as f32,
+            thermal_expansion: GLOBALS.thermal_expansion as f32,
+            rheology: ViscosityModel::Newtonian,
+            elasticity: Elasticity {
+                youngs_modulus: GLOBALS.youngs_modulus as f32,
+                poisson_ratio: GLOBALS.poisson_ratio as f32,
+                artificial_stress: GLOBALS.artificial_stress as f32,
+            },
+            plasticity: DruckerPrager::default(),
+        });
+    }
+    materials
+}
+
+fn register_phases(materials: &mut MaterialRegistry, reference_density: f32, mass: f32) {
+    let sound_speed = GLOBALS.tait_c as f32;
+    let ratio = GLOBALS.phase_density_ratio as f32;
+    let phases = [("light fluid", 1.0, [0.2, 0.45, 0.9]), ("heavy fluid", ratio, [0.9, 0.5, 0.15])];
+    for ((name, relative_density, color), viscosity) in phases.into_iter().zip(GLOBALS.phase_viscosities) {
+        let rest_density = relative_density * reference_density;
+        materials.register(Material {
+            name,
+            kind: MaterialKind::Fluid,
+            rest_density,
+            mass: relative_density * mass,
+            viscosity: viscosity as f32,
+            equation_of_state: equation_of_state(rest_density, sound_speed * (ratio / relative_density).sqrt()),
+            color,
+            conductivity: GLOBALS.thermal_conductivity as f32,
+            specific_heat: GLOBALS.specific_heat as f32,
+            thermal_expansion: GLOBALS.thermal_expansion as f32,
+            rheology: GLOBALS.rheology,
+            elasticity: Elasticity::default(),
+            plasticity: DruckerPrager::default(),
+        });
+    }
+}
+
+/// Whether a particle starts inside the block of elastic solid.
+fn in_solid_region(x: f64, y: f64) -> bool {
+    let [min, max] = GLOBALS.solid_region;
+    (min[0]..=max[0]).contains(&x) && (min[1]..=max[1]).contains(&y)
+}
+
+/// Heated floor and cooled ceiling of the box.
+fn thermal_walls() -> Vec<ThermalWall> {
+    let box_min = GLOBALS.box_min as f32;
+    let box_max = GLOBALS.box_max as f32;
+    let thickness = GLOBALS.thermal_wall_thickness as f32;
+
+    vec![
+        ThermalWall {
+            min: [box_min, box_min, box_min],
+            max: [box_max, box_min + thickness, box_max],
+            temperature: GLOBALS.floor_temperature as f32,
+        },
+        ThermalWall {
+            min: [box_min, box_max - thickness, box_min],
+            max: [box_max, box_max, box_max],
+            temperature: GLOBALS.ceiling_temperature as f32,
+        },
+    ]
+}
+
+/// Equation of state selected by the configuration. The gas model always
+/// closes with the ideal gas law.
+fn equation_of_state(reference_density: f32, sound_speed: f32) -> EquationOfStateModel {
+    let background_pressure = GLOBALS.background_pressure as f32;
+
+    let kind = match GLOBALS.fluid_model {
+        FluidModel::IdealGas => EquationOfStateKind::IdealGas,
+        FluidModel::WeaklyCompressible => GLOBALS.equation_of_state,
+    };
+    match kind {
+        EquationOfStateKind::Tait => EquationOfStateModel::Tait(Tait {
+            reference_density,
+            sound_speed,
+            gamma: GLOBALS.tait_gamma as f32,
+            background_pressure,
+        }),
+        EquationOfStateKind::Linear => EquationOfStateModel::Linear(Linear {
+            reference_density,
+            sound_speed,
+            background_pressure,
+        }),
+        EquationOfStateKind::IdealGas => EquationOfStateModel::IdealGas(IdealGas {
+            gamma: GLOBALS.gas_gamma as f32,
+        }),
+        EquationOfStateKind::StiffenedGas => EquationOfStateModel::StiffenedGas(StiffenedGas {
+            gamma: GLOBALS.gas_gamma as f32,
+            stiffness: GLOBALS.stiffened_pressure as f32,
+        }),
+    }
+}
@@ -0,0 +1,222 @@
+use crate::spatial_hash::{distance_sq, into_indices, sort_by_distance};
+
+const LEAF_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+struct KdNode {
+    start: usize,
+    end: usize,
+    axis: usize,
+    split: f32,
+    children: Option<(usize, usize)>,
+}
+
+/// Kd-tree over particle positions. Unlike the uniform grids it has no
+/// preferred length scale, so it serves queries with per-particle radii.
+#[derive(Debug, Clone, Default)]
+pub struct KdTree {
+    indices: Vec<usize>,
+    nodes: Vec<KdNode>,
+}
+
+fn coordinate(positions: [&[f32]; 3], axis: usize, i: usize) -> f32 {
+    positions[axis][i]
+}
+
+impl KdTree {
+    pub fn build(&mut self, positions: [&[f32]; 3], num_particles: usize) {
+        self.indices.clear();
+        self.indices.extend(0..num_particles);
+        self.nodes.clear();
+
+        if num_particles > 0 {
+            self.build_node(positions, 0, num_particles);
+        }
+    }
+
+    fn build_node(&mut self, positions: [&[f32]; 3], start: usize, end: usize) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(KdNode { start, end, axis: 0, split: 0.0, children: None });
+
+        if end - start <= LEAF_SIZE {
+            return node;
+        }
+
+        // Split along the axis with the largest spread
+        let mut axis = 0;
+        let mut widest = -1.0_f32;
+        for candidate in 0..3 {
+            let mut lo = f32::INFINITY;
+            let mut hi = f32::NEG_INFINITY;
+            for &i in &self.indices[start..end] {
+                let c = coordinate(positions, candidate, i);
+                lo = lo.min(c);
+                hi = hi.max(c);
+            }
+            if hi - lo > widest {
+                widest = hi - lo;
+                axis = candidate;
+            }
+        }
+
+        let mid = start + (end - start) / 2;
+        self.indices[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+            coordinate(positions, axis, a).total_cmp(&coordinate(positions, axis, b))
+        });
+        let split = coordinate(positions, axis, self.indices[mid]);
+
+        let left = self.build_node(positions, start, mid);
+        let right = self.build_node(positions, mid, end);
+
+        let n = &mut self.nodes[node];
+        n.axis = axis;
+        n.split = split;
+        n.children = Some((left, right));
+        node
+    }
+
+    /// Returns every particle within `radius` of `point`, nearest first.
+    pub fn query_radius(&self, positions: [&[f32]; 3], point: [f32; 3], radius: f32) -> Vec<usize> {
+        let mut found = Vec::new();
+        if !self.nodes.is_empty() {
+            self.collect_radius(0, positions, point, radius, &mut found);
+        }
+
+        sort_by_distance(&mut found);
+        into_indices(found)
+    }
+
+    fn collect_radius(
+        &self,
+        node: usize,
+        positions: [&[f32]; 3],
+        point: [f32; 3],
+        radius: f32,
+        found: &mut Vec<(f32, usize)>,
+    ) {
+        let n = &self.nodes[node];
+        match n.children {
+            None => {
+                for &i in &self.indices[n.start..n.end] {
+                    let d2 = distance_sq(positions, i, point);
+                    if d2 <= radius * radius {
+                        found.push((d2, i));
+                    }
+                }
+            }
+            Some((left, right)) => {
+                let diff = point[n.axis] - n.split;
+                if diff <= radius {
+                    self.collect_radius(left, positions, point, radius, found);
+                }
+                if diff >= -radius {
+                    self.collect_radius(right, positions, point, radius, found);
+                }
+            }
+        }
+    }
+
+    /// Returns the `k` particles nearest to `point`, nearest first.
+    pub fn query_nearest(&self, positions: [&[f32]; 3], point: [f32; 3], k: usize) -> Vec<usize> {
+        let mut best = Vec::with_capacity(k + 1);
+        if k > 0 && !self.nodes.is_empty() {
+            self.collect_nearest(0, positions, point, k, &mut best);
+        }
+        into_indices(best)
+    }
+
+    fn collect_nearest(
+        &self,
+        node: usize,
+        positions: [&[f32]; 3],
+        point: [f32; 3],
+        k: usize,
+        best: &mut Vec<(f32, usize)>,
+    ) {
+        let n = &self.nodes[node];
+        match n.children {
+            None => {
+                for &i in &self.indices[n.start..n.end] {
+                    let candidate = (distance_sq(positions, i, point), i);
+                    if best.len() == k && candidate >= best[k - 1] {
+                        continue;
+                    }
+                    let at = best.partition_point(|b| b < &candidate);
+                    best.insert(at, candidate);
+                    best.truncate(k);
+                }
+            }
+            Some((left, right)) => {
+                let diff = point[n.axis] - n.split;
+                let (near, far) = if diff <= 0.0 { (left, right) } else { (right, left) };
+
+                self.collect_nearest(near, positions, point, k, best);
+                if best.len() < k || diff * diff <= best[k - 1].0 {
+                    self.collect_nearest(far, positions, point, k, best);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scattered_points;
+
+    const SLAB: [[f32; 2]; 3] = [[-2.0, 2.0], [-2.0, 2.0], [-0.5, 0.5]];
+
+    fn brute_force(positions: [&[f32]; 3], point: [f32; 3]) -> Vec<(f32, usize)> {
+        let mut all: Vec<(f32, usize)> = (0..positions[0].len())
+            .map(|i| (distance_sq(positions, i, point), i))
+            .collect();
+        sort_by_distance(&mut all);
+        all
+    }
+
+    #[test]
+    fn empty_tree_returns_nothing() {
+        let mut tree = KdTree::default();
+        tree.build([&[], &[], &[]], 0);
+        assert!(tree.query_radius([&[], &[], &[]], [0.0; 3], 1.0).is_empty());
+        assert!(tree.query_nearest([&[], &[], &[]], [0.0; 3], 3).is_empty());
+    }
+
+    #[test]
+    fn radius_queries_match_brute_force() {
+        let (px, py, pz) = scattered_points(300, 3, SLAB);
+        let positions = [&px[..], &py[..], &pz[..]];
+        let mut tree = KdTree::default();
+        tree.build(positions, px.len());
+
+        for point in [[0.0, 0.0, 0.0], [1.7, -1.2, 0.3], [5.0, 5.0, 5.0]] {
+            for radius in [0.05, 0.3, 1.0, 10.0] {
+                let expected: Vec<usize> = brute_force(positions, point)
+                    .into_iter()
+                    .filter(|&(d2, _)| d2 <= radius * radius)
+                    .map(|(_, i)| i)
+                    .collect();
+                assert_eq!(tree.query_radius(positions, point, radius), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_queries_match_brute_force() {
+        let (px, py, pz) = scattered_points(300, 5, SLAB);
+        let positions = [&px[..], &py[..], &pz[..]];
+        let mut tree = KdTree::default();
+        tree.build(positions, px.len());
+
+        for point in [[0.0, 0.0, 0.0], [-1.9, 1.9, -0.4], [-8.0, 0.0, 0.0]] {
+            for k in [1, 7, 64, 400] {
+                let expected: Vec<usize> = brute_force(positions, point)
+                    .into_iter()
+                    .take(k)
+                    .map(|(_, i)| i)
+                    .collect();
+                assert_eq!(tree.query_nearest(positions, point, k), expected);
+            }
+        }
+    }
+}
@@ -1,7 +1,25 @@
 use std::f64::consts::PI;
 
+/// Normalization of the cubic spline with support radius `1 / inv_h` in
+/// `dim` dimensions.
+fn normalization(inv_h: f64, dim: usize) -> f64 {
+    match dim {
+        1 => inv_h / 3.0,
+        2 => 10.0 * inv_h * inv_h / (7.0 * PI),
+        _ => 2.0 * inv_h * inv_h * inv_h / PI,
+    }
+}
+
 pub fn kernel(r: f64, inv_h: f64) -> f64 {
-    let norm = 10.0 * inv_h * inv_h / (7.0 * PI);
+    kernel_nd(r, inv_h, 2)
+}
+
+pub fn d_kernel(r: f64, inv_h: f64) -> f64 {
+    d_kernel_nd(r, inv_h, 2)
+}
+
+pub fn kernel_nd(r: f64, inv_h: f64, dim: usize) -> f64 {
+    let norm = normalization(inv_h, dim);
     
     let q = 2.0 * r * inv_h;
     
@@ -15,8 +33,8 @@ pub fn kernel(r: f64, inv_h: f64) -> f64 {
     }
 }
 
-pub fn d_kernel(r: f64, inv_h: f64) -> f64 {
-    let norm = 10.0 * inv_h * inv_h / (7.0 * PI);
+pub fn d_kernel_nd(r: f64, inv_h: f64, dim: usize) -> f64 {
+    let norm = normalization(inv_h, dim);
     
     let q = 2.0 * r * inv_h;
     
@@ -30,6 +48,12 @@ pub fn d_kernel(r: f64, inv_h: f64) -> f64 {
     }
 }
 
+/// Derivative of the kernel with respect to its support radius `h`, using
+/// `W = h^-d f(r / h)`.
+pub fn dh_kernel_nd(r: f64, inv_h: f64, dim: usize) -> f64 {
+    -(dim as f64 * kernel_nd(r, inv_h, dim) + r * d_kernel_nd(r, inv_h, dim)) * inv_h
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,6 +98,7 @@ mod tests {
             assert!(w0 > 0.0, "kernel at origin should be positive for inv_h={}", inv_h);
 
             let mut prev = w0;
+            #[allow(clippy::needless_range_loop)]
             for i in 1..rs.len() {
                 let w = kernel(rs[i], inv_h);
                 assert!(w <= prev + 1e-12, "kernel should be non-increasing for inv_h={}, r={}", inv_h, rs[i]);
@@ -139,4 +164,46 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_1d_and_3d_normalization() {
+        for &inv_h in &INV_HS {
+            let h = 1.0 / inv_h;
+            let n = 20000;
+            let step = h / n as f64;
+
+            let mut line = 0.0;
+            let mut shell = 0.0;
+            for i in 0..n {
+                let r = (i as f64 + 0.5) * step;
+                line += 2.0 * kernel_nd(r, inv_h, 1) * step;
+                shell += 4.0 * PI * r * r * kernel_nd(r, inv_h, 3) * step;
+            }
+
+            assert!((line - 1.0).abs() < 1e-6, "1D normalization off for inv_h={}: {}", inv_h, line);
+            assert!((shell - 1.0).abs() < 1e-6, "3D normalization off for inv_h={}: {}", inv_h, shell);
+        }
+    }
+
+    #[test]
+    fn test_h_derivative_consistency() {
+        for dim in 1..=3 {
+            for &inv_h in &INV_HS {
+                let h = 1.0 / inv_h;
+                let delta = h * 1e-6;
+
+                for r in scan(0.0, h, h / 300.0) {
+                    let fd = (kernel_nd(r, 1.0 / (h + delta), dim) - kernel_nd(r, 1.0 / (h - delta), dim)) / (2.0 * delta);
+                    let d = dh_kernel_nd(r, inv_h, dim);
+                    let tol = 1e-8 * inv_h.powi(dim as i32 + 1) + 1e-4 * d.abs().max(fd.abs());
+
+                    assert!(
+                        (fd - d).abs() <= tol,
+                        "h derivative mismatch for dim={}, inv_h={}, r={}: finite_diff={}, analytical={}",
+                        dim, inv_h, r, fd, d
+                    );
+                }
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,142 @@
+use crate::elasticity::{determinant, inverse, mul_vector, transpose, unit_block, Matrix3, IDENTITY};
+use crate::kernel::d_kernel_nd;
+use crate::material::Material;
+
+// Moment determinant below which a particle keeps the identity; isolated
+// splash particles would otherwise get huge corrections that feed energy
+// into the flow
+const MIN_MOMENT_DETERMINANT: f32 = 0.02;
+
+/// Borrowed per-particle fields read by the kernel gradient correction.
+pub struct CorrectionParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// Renormalization matrices of Bonet & Lok (1999),
+/// `L_i = (sum_j V_j (x_j - x_i) (x) grad_i W_ij)^-T`. Gradients corrected
+/// with `L_i grad_i W_ij` reproduce those of linear fields exactly, including
+/// where the kernel support is truncated by a free surface or wall. Particles
+/// with too few neighbors for a well-conditioned moment keep the identity.
+pub fn correction_matrices(
+    particles: &CorrectionParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    correction: &mut [Matrix3],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+
+    let mut moment = vec![unit_block(dim); rho.len()];
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let material_i = &materials[particles.material[i]];
+            let material_j = &materials[particles.material[j]];
+            if !material_i.interacts() || !material_j.interacts() || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad_scale = d_kernel_nd(d as f64, inv_h, dim) as f32 / d;
+            let volume_i = material_i.mass / rho[i];
+            let volume_j = material_j.mass / rho[j];
+
+            // (x_j - x_i) (x) grad_i W_ij = (x_i - x_j) (x) grad_j W_ji
+            for a in 0..dim {
+                for b in 0..dim {
+                    let term = dr[a] * dr[b] * grad_scale;
+                    moment[i][a][b] -= volume_j * term;
+                    moment[j][a][b] -= volume_i * term;
+                }
+            }
+        }
+    }
+
+    for (correction, moment) in correction.iter_mut().zip(&moment) {
+        *correction = if determinant(moment) > MIN_MOMENT_DETERMINANT {
+            inverse(moment).map(|inv| transpose(&inv)).unwrap_or(IDENTITY)
+        } else {
+            IDENTITY
+        };
+    }
+}
+
+/// Kernel gradient `grad_i W_ij` corrected with the matrix of particle `i`.
+pub fn corrected_gradient(correction: &Matrix3, gradient: [f32; 3]) -> [f32; 3] {
+    mul_vector(correction, gradient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{half_neighbor_lists, square_lattice, water, SMOOTHING_RADIUS};
+
+    const SIDE: usize = 10;
+
+    /// SPH gradient `sum_j V_j (f_j - f_i) L_i grad_i W_ij` of the field
+    /// `f(x, y) = 2 x - 3 y + 1` on a square block, with or without the
+    /// correction. Exactness for linear fields is what defines `L_i`; the
+    /// pressure force built on it is tested in `simulation`.
+    fn linear_field_gradients(corrected: bool) -> Vec<[f32; 3]> {
+        let position = square_lattice(SIDE);
+        let n = SIDE * SIDE;
+        let neighbors = half_neighbor_lists(&position);
+        let rho = vec![1.0; n];
+        let material = vec![0; n];
+        let materials = [water()];
+
+        let mut correction = vec![IDENTITY; n];
+        if corrected {
+            let particles = CorrectionParticles {
+                position: [&position[0], &position[1], &position[2]],
+                rho: &rho,
+                material: &material,
+            };
+            correction_matrices(&particles, &materials, &neighbors, SMOOTHING_RADIUS, 2, &mut correction);
+        }
+
+        let field: Vec<f32> = (0..n).map(|i| 2.0 * position[0][i] - 3.0 * position[1][i] + 1.0).collect();
+        let inv_h = 1.0 / SMOOTHING_RADIUS as f64;
+        let volume = materials[0].mass;
+        let mut gradient = vec![[0.0; 3]; n];
+        for (i, list) in neighbors.iter().enumerate() {
+            for &j in list {
+                let dr: [f32; 3] = std::array::from_fn(|a| position[a][i] - position[a][j]);
+                let d = dr.iter().map(|c| c * c).sum::<f32>().sqrt();
+                let grad = dr.map(|c| c * d_kernel_nd(d as f64, inv_h, 2) as f32 / d);
+                let grad_i = corrected_gradient(&correction[i], grad);
+                let grad_j = corrected_gradient(&correction[j], grad.map(|c| -c));
+                for a in 0..3 {
+                    gradient[i][a] += volume * (field[j] - field[i]) * grad_i[a];
+                    gradient[j][a] += volume * (field[i] - field[j]) * grad_j[a];
+                }
+            }
+        }
+        gradient
+    }
+
+    #[test]
+    fn corrected_gradient_of_linear_field_is_exact() {
+        for (i, gradient) in linear_field_gradients(true).iter().enumerate() {
+            assert!((gradient[0] - 2.0).abs() < 1e-4, "particle {i}: {gradient:?}");
+            assert!((gradient[1] + 3.0).abs() < 1e-4, "particle {i}: {gradient:?}");
+            assert_eq!(gradient[2], 0.0);
+        }
+    }
+
+    #[test]
+    fn uncorrected_gradient_fails_at_the_corner() {
+        let gradient = linear_field_gradients(false);
+        assert!((gradient[0][0] - 2.0).abs() > 0.1, "{:?}", gradient[0]);
+    }
+}
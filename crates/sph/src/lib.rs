@@ -4,3 +4,30 @@ pub mod state;
 pub mod initial_conditions;
 pub mod constants;
 pub mod simulation;
+pub mod verlet;
+pub mod diagnostics;
+pub mod neighbor_search;
+pub mod kd_tree;
+pub mod smoothing_length;
+pub mod gas_dynamics;
+pub mod equation_of_state;
+pub mod self_gravity;
+pub mod multiphase;
+pub mod material;
+pub mod heat;
+pub mod rheology;
+pub mod elasticity;
+pub mod granular;
+pub mod turbulence;
+pub mod continuity;
+pub mod density_filter;
+pub mod kernel_correction;
+pub mod shifting;
+pub mod free_surface;
+pub mod vorticity;
+pub mod xsph;
+pub mod operators;
+pub mod eulerian_grid;
+pub mod surface_reconstruction;
+#[cfg(test)]
+mod test_support;
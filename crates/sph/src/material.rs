@@ -0,0 +1,176 @@
+use std::ops::Index;
+
+use crate::equation_of_state::EquationOfStateModel;
+use crate::rheology::ViscosityModel;
+use crate::elasticity::Elasticity;
+use crate::granular::DruckerPrager;
+
+/// How particles of a material take part in the simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaterialKind {
+    /// Moves under pressure, viscous and body forces
+    Fluid,
+    /// Elastic body held together by stresses relative to its reference
+    /// configuration
+    Solid,
+    /// Elastoplastic granular medium such as sand
+    Granular,
+    /// Held in place; contributes density and pressure to fluid near walls
+    Boundary,
+    /// Massless marker advected with the local fluid velocity
+    Tracer,
+}
+
+/// Properties shared by all particles of one material.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    pub name: &'static str,
+    pub kind: MaterialKind,
+    pub rest_density: f32,
+    pub mass: f32,
+    /// Dynamic viscosity of a Newtonian material
+    pub viscosity: f32,
+    pub equation_of_state: EquationOfStateModel,
+    /// Display color as linear RGB
+    pub color: [f32; 3],
+    pub conductivity: f32,
+    pub specific_heat: f32,
+    /// Volumetric expansion coefficient for Boussinesq buoyancy
+    pub thermal_expansion: f32,
+    /// Dependence of the viscosity on shear rate
+    pub rheology: ViscosityModel,
+    /// Elastic moduli of a solid or granular material
+    pub elasticity: Elasticity,
+    /// Yield criterion of a granular material
+    pub plasticity: DruckerPrager,
+}
+
+impl Material {
+    /// Whether the particle is moved by the forces acting on it.
+    pub fn is_mobile(&self) -> bool {
+        matches!(self.kind, MaterialKind::Fluid | MaterialKind::Solid | MaterialKind::Granular)
+    }
+
+    pub fn is_fluid(&self) -> bool {
+        self.kind == MaterialKind::Fluid
+    }
+
+    pub fn is_solid(&self) -> bool {
+        self.kind == MaterialKind::Solid
+    }
+
+    pub fn is_granular(&self) -> bool {
+        self.kind == MaterialKind::Granular
+    }
+
+    /// Whether particles of the material hold each other together through
+    /// their stress tensor instead of pressure.
+    pub fn carries_stress(&self) -> bool {
+        self.is_solid() || self.is_granular()
+    }
+
+    /// Whether the particle contributes to the density of and exerts forces
+    /// on its neighbors. Tracers only observe the flow.
+    pub fn interacts(&self) -> bool {
+        self.kind != MaterialKind::Tracer
+    }
+}
+
+/// Table of the materials in a scene. Particles refer to a material by the
+/// index `register` returned for it.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialRegistry {
+    materials: Vec<Material>,
+}
+
+impl MaterialRegistry {
+    pub fn register(&mut self, material: Material) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    /// Index of the first material registered under `name`.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.materials.iter().position(|material| material.name == name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[Material] {
+        &self.materials
+    }
+}
+
+impl Index<usize> for MaterialRegistry {
+    type Output = Material;
+
+    fn index(&self, index: usize) -> &Material {
+        &self.materials[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::equation_of_state::Linear;
+
+    fn material(name: &'static str, kind: MaterialKind) -> Material {
+        Material {
+            name,
+            kind,
+            rest_density: 1000.0,
+            mass: 0.1,
+            viscosity: 1e-3,
+            equation_of_state: EquationOfStateModel::Linear(Linear {
+                reference_density: 1000.0,
+                sound_speed: 20.0,
+                background_pressure: 0.0,
+            }),
+            color: [0.2, 0.4, 0.9],
+            conductivity: 0.6,
+            specific_heat: 4200.0,
+            thermal_expansion: 2e-4,
+            rheology: ViscosityModel::Newtonian,
+            elasticity: Elasticity::default(),
+            plasticity: DruckerPrager::default(),
+        }
+    }
+
+    #[test]
+    fn registered_materials_are_indexed_in_order() {
+        let mut registry = MaterialRegistry::default();
+        assert!(registry.is_empty());
+
+        let water = registry.register(material("water", MaterialKind::Fluid));
+        let wall = registry.register(material("wall", MaterialKind::Boundary));
+        let dye = registry.register(material("dye", MaterialKind::Tracer));
+
+        assert_eq!((water, wall, dye), (0, 1, 2));
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry[wall].kind, MaterialKind::Boundary);
+        assert_eq!(registry.find("dye"), Some(dye));
+        assert_eq!(registry.find("oil"), None);
+    }
+
+    #[test]
+    fn kinds_decide_motion_and_interaction() {
+        let fluid = material("water", MaterialKind::Fluid);
+        let jelly = material("jelly", MaterialKind::Solid);
+        let boundary = material("wall", MaterialKind::Boundary);
+        let tracer = material("dye", MaterialKind::Tracer);
+
+        assert!(fluid.is_mobile() && fluid.interacts());
+        assert!(jelly.is_mobile() && jelly.interacts() && jelly.is_solid());
+        let sand = material("sand", MaterialKind::Granular);
+        assert!(sand.is_mobile() && sand.carries_stress() && !sand.is_solid());
+        assert!(!fluid.carries_stress());
+        assert!(!boundary.is_mobile() && boundary.interacts());
+        assert!(!tracer.is_mobile() && !tracer.interacts());
+    }
+}
@@ -0,0 +1,358 @@
+use crate::kernel::{d_kernel_nd, kernel_nd};
+use crate::material::Material;
+
+/// Borrowed per-particle fields read by the multiphase equations.
+pub struct PhaseParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub p: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// Density from the kernel-weighted number density, `rho_i = m_i sum_j W_ij`
+/// (Hu & Adams 2006). Neighbors of another phase only contribute volume, so
+/// the density stays sharp across interfaces instead of being smeared by
+/// their mass. Tracers sample the density around them without adding to it.
+pub fn phase_densities(
+    position: [&[f32]; 3],
+    neighbors: &[Vec<usize>],
+    material: &[usize],
+    materials: &[Material],
+    smoothing_radius: f32,
+    dim: usize,
+    rho: &mut [f32],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let self_weight = kernel_nd(0.0, inv_h, dim) as f32;
+    for (rho, &k) in rho.iter_mut().zip(material) {
+        *rho = if materials[k].interacts() { self_weight } else { 0.0 };
+    }
+
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let dx = position[0][i] - position[0][j];
+            let dy = position[1][i] - position[1][j];
+            let dz = position[2][i] - position[2][j];
+            let r = ((dx * dx + dy * dy + dz * dz) as f64).sqrt();
+
+            let w = kernel_nd(r, inv_h, dim) as f32;
+            if materials[material[j]].interacts() {
+                rho[i] += w;
+            }
+            if materials[material[i]].interacts() {
+                rho[j] += w;
+            }
+        }
+    }
+
+    for (rho, &k) in rho.iter_mut().zip(material) {
+        *rho *= materials[k].mass;
+    }
+}
+
+/// Adds pressure accelerations in the particle-volume form of Hu & Adams
+/// (2006). Pressure enters through `V_i = m_i / rho_i`, so the force does
+/// not jump at a density discontinuity. The matching viscous term is
+/// `rheology::accumulate_viscous_forces`.
+pub fn accumulate_multiphase_forces(
+    particles: &PhaseParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    accel: [&mut [f32]; 3],
+) {
+    let [ax, ay, az] = accel;
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+    let p = particles.p;
+
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let phase_i = &materials[particles.material[i]];
+            let phase_j = &materials[particles.material[j]];
+            if !phase_i.interacts() || !phase_j.interacts() || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+            // Particles of one solid or granular body act through their stress
+            if phase_i.carries_stress() && particles.material[i] == particles.material[j] {
+                continue;
+            }
+
+            let dx = x[i] - x[j];
+            let dy = y[i] - y[j];
+            let dz = z[i] - z[j];
+            let r2 = dx * dx + dy * dy + dz * dz;
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad_scale = d_kernel_nd(d as f64, inv_h, dim) as f32 / d;
+
+            let volume_i = phase_i.mass / rho[i];
+            let volume_j = phase_j.mass / rho[j];
+
+            // Force on i
+            let pressure = -(p[i] * volume_i * volume_i + p[j] * volume_j * volume_j) * grad_scale;
+            let fx = pressure * dx;
+            let fy = pressure * dy;
+            let fz = pressure * dz;
+
+            ax[i] += fx / phase_i.mass;
+            ay[i] += fy / phase_i.mass;
+            az[i] += fz / phase_i.mass;
+            ax[j] -= fx / phase_j.mass;
+            ay[j] -= fy / phase_j.mass;
+            az[j] -= fz / phase_j.mass;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::equation_of_state::{EquationOfState, EquationOfStateModel, Tait};
+    use crate::material::MaterialKind;
+    use crate::kd_tree::KdTree;
+    use crate::neighbor_search::NeighborSearch;
+    use crate::rheology::{accumulate_viscous_forces, FlowParticles};
+    use crate::test_support::water;
+
+    const SIDE: usize = 24;
+    const SOUND_SPEED: f32 = 10.0;
+
+    /// Light and heavy phase with a 1:10 density ratio and equal stiffness,
+    /// so both compress alike under the same pressure.
+    fn phases(spacing: f32) -> [Material; 2] {
+        let phase = |rest_density: f32, viscosity: f32| Material {
+            rest_density,
+            mass: rest_density * spacing * spacing,
+            viscosity,
+            equation_of_state: EquationOfStateModel::Tait(Tait {
+                reference_density: rest_density,
+                sound_speed: SOUND_SPEED * (10.0 / rest_density).sqrt(),
+                gamma: 7.0,
+                background_pressure: 20.0,
+            }),
+            ..water()
+        };
+        [phase(1.0, 0.002), phase(10.0, 0.02)]
+    }
+
+    /// Square of heavy fluid in the middle of a doubly periodic unit box of
+    /// light fluid, on a slightly jittered lattice.
+    struct Drop {
+        position: [Vec<f32>; 3],
+        velocity: [Vec<f32>; 3],
+        phase: Vec<usize>,
+        phases: [Material; 2],
+    }
+
+    impl Drop {
+        fn new() -> Self {
+            let spacing = 1.0 / SIDE as f32;
+            let mut drop = Drop {
+                position: [Vec::new(), Vec::new(), Vec::new()],
+                velocity: [Vec::new(), Vec::new(), Vec::new()],
+                phase: Vec::new(),
+                phases: phases(spacing),
+            };
+            for i in 0..SIDE {
+                for j in 0..SIDE {
+                    let jitter = 0.02 * spacing * (((i * 7 + j * 13) % 5) as f32 - 2.0) / 2.0;
+                    let x = (i as f32 + 0.5) * spacing + jitter;
+                    let y = (j as f32 + 0.5) * spacing - jitter;
+                    let inside = (0.3..0.7).contains(&x) && (0.3..0.7).contains(&y);
+                    drop.position[0].push(x);
+                    drop.position[1].push(y);
+                    drop.position[2].push(0.0);
+                    drop.phase.push(inside as usize);
+                }
+            }
+            for v in drop.velocity.iter_mut() {
+                v.resize(SIDE * SIDE, 0.0);
+            }
+            drop
+        }
+
+        /// Densities and accelerations with periodic images across the box.
+        fn derivatives(&self, smoothing_radius: f32) -> (Vec<f32>, [Vec<f32>; 3]) {
+            let n = self.phase.len();
+            let mut images = Vec::new();
+            for i in 0..n {
+                for shift_x in [-1.0, 0.0, 1.0_f32] {
+                    for shift_y in [-1.0, 0.0, 1.0_f32] {
+                        let x = self.position[0][i] + shift_x;
+                        let y = self.position[1][i] + shift_y;
+                        let near = |c: f32| c > -smoothing_radius && c < 1.0 + smoothing_radius;
+                        if (shift_x, shift_y) != (0.0, 0.0) && near(x) && near(y) {
+                            images.push((i, shift_x, shift_y));
+                        }
+                    }
+                }
+            }
+
+            let extend = |field: &[f32], shift: fn(&(usize, f32, f32)) -> f32| -> Vec<f32> {
+                let mut out = field.to_vec();
+                out.extend(images.iter().map(|image| field[image.0] + shift(image)));
+                out
+            };
+            let position = [
+                extend(&self.position[0], |image| image.1),
+                extend(&self.position[1], |image| image.2),
+                extend(&self.position[2], |_| 0.0),
+            ];
+            let velocity = [
+                extend(&self.velocity[0], |_| 0.0),
+                extend(&self.velocity[1], |_| 0.0),
+                extend(&self.velocity[2], |_| 0.0),
+            ];
+            let mut phase = self.phase.clone();
+            phase.extend(images.iter().map(|image| self.phase[image.0]));
+
+            let position = [&position[0][..], &position[1][..], &position[2][..]];
+            let total = phase.len();
+            let mut neighbors = vec![Vec::new(); total];
+            KdTree::default().find_neighbors(position, &vec![smoothing_radius; total], &mut neighbors);
+
+            let mut rho = vec![0.0; total];
+            phase_densities(position, &neighbors, &phase, &self.phases, smoothing_radius, 2, &mut rho);
+            for (k, image) in images.iter().enumerate() {
+                rho[n + k] = rho[image.0];
+            }
+            let p: Vec<f32> = (0..total)
+                .map(|k| self.phases[phase[k]].equation_of_state.pressure(rho[k], 0.0))
+                .collect();
+
+            let mut accel = [vec![0.0; total], vec![0.0; total], vec![0.0; total]];
+            let particles = PhaseParticles { position, rho: &rho, p: &p, material: &phase };
+            let [ax, ay, az] = &mut accel;
+            accumulate_multiphase_forces(&particles, &self.phases, &neighbors, smoothing_radius, 2, [ax, ay, az]);
+
+            let flow = FlowParticles {
+                position,
+                velocity: [&velocity[0][..], &velocity[1][..], &velocity[2][..]],
+                rho: &rho,
+                material: &phase,
+            };
+            let viscosity: Vec<f32> = phase.iter().map(|&k| self.phases[k].viscosity).collect();
+            let [ax, ay, az] = &mut accel;
+            accumulate_viscous_forces(&flow, &self.phases, &viscosity, &neighbors, smoothing_radius, 2, [ax, ay, az]);
+
+            rho.truncate(n);
+            for a in accel.iter_mut() {
+                a.truncate(n);
+            }
+            (rho, accel)
+        }
+
+        fn run(&mut self, smoothing_radius: f32, dt: f32, steps: usize) -> Vec<f32> {
+            let (_, mut accel) = self.derivatives(smoothing_radius);
+            for _ in 0..steps {
+                for i in 0..self.phase.len() {
+                    for ((v, x), a) in self.velocity.iter_mut().zip(self.position.iter_mut()).zip(&accel) {
+                        v[i] += 0.5 * dt * a[i];
+                        x[i] = (x[i] + dt * v[i]).rem_euclid(1.0);
+                    }
+                }
+                (_, accel) = self.derivatives(smoothing_radius);
+                for i in 0..self.phase.len() {
+                    for (v, a) in self.velocity.iter_mut().zip(&accel) {
+                        v[i] += 0.5 * dt * a[i];
+                    }
+                }
+            }
+            self.derivatives(smoothing_radius).0
+        }
+
+        fn max_speed(&self) -> f32 {
+            (0..self.phase.len())
+                .map(|i| (self.velocity[0][i].powi(2) + self.velocity[1][i].powi(2)).sqrt())
+                .fold(0.0, f32::max)
+        }
+    }
+
+    #[test]
+    fn densities_stay_sharp_across_interface() {
+        let drop = Drop::new();
+        let smoothing_radius = 3.0 / SIDE as f32;
+        let (rho, _) = drop.derivatives(smoothing_radius);
+
+        for (i, &k) in drop.phase.iter().enumerate() {
+            let rest = drop.phases[k].rest_density;
+            assert!((rho[i] - rest).abs() < 0.03 * rest, "particle {} of phase {}: rho {}", i, k, rho[i]);
+        }
+    }
+
+    #[test]
+    fn interface_with_tenfold_density_ratio_stays_sharp() {
+        let mut drop = Drop::new();
+        let smoothing_radius = 3.0 / SIDE as f32;
+        let half_spacing = 0.5 / SIDE as f32;
+
+        // Time step limited by the faster sound speed of the light phase
+        let light_sound_speed = SOUND_SPEED * 10.0_f32.sqrt();
+        let dt = 0.1 * smoothing_radius / light_sound_speed;
+        let rho = drop.run(smoothing_radius, dt, 200);
+
+        // The jitter only excites weak sound waves, not interface currents
+        assert!(drop.max_speed() < 0.03 * light_sound_speed, "max speed {}", drop.max_speed());
+
+        for (i, &k) in drop.phase.iter().enumerate() {
+            let rest = drop.phases[k].rest_density;
+            assert!((rho[i] - rest).abs() < 0.03 * rest, "particle {} of phase {}: rho {}", i, k, rho[i]);
+
+            // Neither phase crosses into the other
+            let within = |margin: f32| {
+                (0..2).all(|d| drop.position[d][i] > 0.3 + margin && drop.position[d][i] < 0.7 - margin)
+            };
+            if k == 1 {
+                assert!(within(-half_spacing), "heavy particle {} left the drop", i);
+            } else {
+                assert!(!within(half_spacing), "light particle {} entered the drop", i);
+            }
+        }
+    }
+
+    #[test]
+    fn tracers_leave_neighbor_densities_unchanged() {
+        let spacing = 1.0 / SIDE as f32;
+        let smoothing_radius = 3.0 * spacing;
+        let [fluid, _] = phases(spacing);
+        let tracer = Material { kind: MaterialKind::Tracer, ..fluid };
+        let materials = [fluid, tracer];
+
+        let mut px = Vec::new();
+        let mut py = Vec::new();
+        for i in 0..8 {
+            for j in 0..8 {
+                px.push(i as f32 * spacing);
+                py.push(j as f32 * spacing);
+            }
+        }
+        let n = px.len();
+        let density = |material: &[usize], px: &[f32], py: &[f32]| {
+            let pz = vec![0.0; px.len()];
+            let positions = [px, py, &pz[..]];
+            let mut neighbors = vec![Vec::new(); px.len()];
+            KdTree::default().find_neighbors(positions, &vec![smoothing_radius; px.len()], &mut neighbors);
+            let mut rho = vec![0.0; px.len()];
+            phase_densities(positions, &neighbors, material, &materials, smoothing_radius, 2, &mut rho);
+            rho
+        };
+        let without = density(&vec![0; n], &px, &py);
+
+        // A tracer dropped between the lattice sites
+        px.push(3.5 * spacing);
+        py.push(3.5 * spacing);
+        let mut material = vec![0; n];
+        material.push(1);
+        let with = density(&material, &px, &py);
+
+        assert_eq!(&with[..n], &without[..]);
+        // The tracer still samples the density of the fluid around it
+        assert!((with[n] - without[3 * 8 + 3]).abs() < 0.1 * without[3 * 8 + 3]);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::kd_tree::KdTree;
+use crate::spatial_hash::{find_neighbors, populate_grid, Grid, HashedGrid};
+
+/// Builds neighbor lists from particle positions. Lists follow the convention
+/// of `spatial_hash::find_neighbors`: particle `i` only lists partners `j > i`.
+/// Every pair closer than the larger of the two search radii is included;
+/// implementations may also list more distant candidates.
+pub trait NeighborSearch {
+    fn find_neighbors(&mut self, positions: [&[f32]; 3], radii: &[f32], neighbors: &mut [Vec<usize>]);
+}
+
+/// Borrowed view of the dense grid stored in `State`, so it can be driven
+/// through `NeighborSearch`. Radii may not exceed the cell length.
+pub struct UniformGrid<'a> {
+    pub grid: &'a Grid,
+    pub contents: &'a mut [Vec<usize>],
+    pub grid_map: &'a mut [usize],
+    pub inv_cell_length: f32,
+}
+
+impl NeighborSearch for UniformGrid<'_> {
+    fn find_neighbors(&mut self, positions: [&[f32]; 3], radii: &[f32], neighbors: &mut [Vec<usize>]) {
+        debug_assert!(radii.iter().all(|&r| r * self.inv_cell_length <= 1.0 + 1e-6));
+
+        populate_grid(
+            positions[0],
+            positions[1],
+            positions[2],
+            self.grid,
+            self.contents,
+            self.grid_map,
+            neighbors.len(),
+            self.inv_cell_length,
+        );
+        find_neighbors(self.grid, self.contents, neighbors);
+    }
+}
+
+impl NeighborSearch for HashedGrid {
+    fn find_neighbors(&mut self, positions: [&[f32]; 3], radii: &[f32], neighbors: &mut [Vec<usize>]) {
+        debug_assert!(radii.iter().all(|&r| r * self.inv_cell_length <= 1.0 + 1e-6));
+
+        self.populate(positions[0], positions[1], positions[2], neighbors.len());
+        HashedGrid::find_neighbors(self, neighbors);
+    }
+}
+
+impl NeighborSearch for KdTree {
+    fn find_neighbors(&mut self, positions: [&[f32]; 3], radii: &[f32], neighbors: &mut [Vec<usize>]) {
+        for neighbor_list in neighbors.iter_mut() {
+            neighbor_list.clear();
+        }
+
+        self.build(positions, neighbors.len());
+
+        // Each particle gathers within its own radius; the pair is filed under
+        // the lower index so pairs seen from both sides are deduplicated below.
+        for i in 0..neighbors.len() {
+            let point = [positions[0][i], positions[1][i], positions[2][i]];
+            for j in self.query_radius(positions, point, radii[i]) {
+                if i < j {
+                    neighbors[i].push(j);
+                } else if j < i {
+                    neighbors[j].push(i);
+                }
+            }
+        }
+
+        for neighbor_list in neighbors.iter_mut() {
+            neighbor_list.sort_unstable();
+            neighbor_list.dedup();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spatial_hash::compute_grid;
+    use crate::test_support::scattered_points;
+
+    const PLANE: [[f32; 2]; 3] = [[-2.0, 2.0], [-2.0, 2.0], [0.0, 0.0]];
+
+    fn exact_pairs(positions: [&[f32]; 3], radii: &[f32]) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        for i in 0..radii.len() {
+            for j in i + 1..radii.len() {
+                let dx = positions[0][i] - positions[0][j];
+                let dy = positions[1][i] - positions[1][j];
+                let dz = positions[2][i] - positions[2][j];
+                let r = radii[i].max(radii[j]);
+                if dx * dx + dy * dy + dz * dz <= r * r {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs
+    }
+
+    fn listed_pairs(neighbors: &[Vec<usize>]) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        for (i, list) in neighbors.iter().enumerate() {
+            for &j in list {
+                assert!(j > i);
+                pairs.push((i, j));
+            }
+        }
+        pairs.sort_unstable();
+        pairs
+    }
+
+    fn assert_covers(search: &mut impl NeighborSearch, positions: [&[f32]; 3], radii: &[f32]) {
+        let mut neighbors = vec![Vec::new(); radii.len()];
+        search.find_neighbors(positions, radii, &mut neighbors);
+        let listed = listed_pairs(&neighbors);
+
+        for pair in exact_pairs(positions, radii) {
+            assert!(listed.binary_search(&pair).is_ok(), "missing pair {:?}", pair);
+        }
+    }
+
+    #[test]
+    fn all_backends_cover_uniform_radius_pairs() {
+        let (px, py, pz) = scattered_points(150, 17, PLANE);
+        let positions = [&px[..], &py[..], &pz[..]];
+        let radius = 0.4;
+        let radii = vec![radius; px.len()];
+
+        let grid = compute_grid(&[&[-2.0_f32, 2.0_f32], &[-2.0_f32, 2.0_f32], &[0.0_f32, 0.0_f32]], radius);
+        let n_cells = grid.count.iter().product();
+        let mut contents = vec![Vec::new(); n_cells];
+        let mut grid_map = vec![0; px.len()];
+        let mut uniform = UniformGrid {
+            grid: &grid,
+            contents: &mut contents,
+            grid_map: &mut grid_map,
+            inv_cell_length: 1.0 / radius,
+        };
+        assert_covers(&mut uniform, positions, &radii);
+
+        assert_covers(&mut HashedGrid::new(radius, 64), positions, &radii);
+        assert_covers(&mut KdTree::default(), positions, &radii);
+    }
+
+    #[test]
+    fn kd_tree_lists_exactly_pairs_within_variable_radii() {
+        let (px, py, pz) = scattered_points(150, 17, PLANE);
+        let positions = [&px[..], &py[..], &pz[..]];
+        let radii: Vec<f32> = (0..px.len()).map(|i| 0.1 + 0.5 * (i % 7) as f32 / 6.0).collect();
+
+        let mut neighbors = vec![Vec::new(); px.len()];
+        KdTree::default().find_neighbors(positions, &radii, &mut neighbors);
+
+        assert_eq!(listed_pairs(&neighbors), exact_pairs(positions, &radii));
+    }
+}
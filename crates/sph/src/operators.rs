@@ -0,0 +1,401 @@
+use crate::kernel::{d_kernel_nd, kernel_nd};
+use crate::material::Material;
+
+/// How a first-derivative operator pairs the field values of two particles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Formulation {
+    /// `sum_j V_j (f_j - f_i) grad_i W_ij`; vanishes for constant fields and
+    /// recovers the slope of linear ones away from the free surface
+    Difference,
+    /// `rho_i sum_j m_j (f_i / rho_i^2 + f_j / rho_j^2) grad_i W_ij`; antisymmetric
+    /// in the pair, so forces built from it conserve momentum
+    Symmetric,
+    /// `sum_j V_j f_j grad_i W_ij`, the plain SPH estimate; it does not vanish
+    /// for constant fields where the kernel support is cut off, so the
+    /// gradient of a unit field leans into the fluid at a free surface
+    Summation,
+}
+
+/// Borrowed per-particle fields the operators need besides the field itself.
+pub struct OperatorParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// Neighbor list and kernel over which the operators sum.
+pub struct Neighborhood<'a> {
+    pub materials: &'a [Material],
+    pub neighbors: &'a [Vec<usize>],
+    pub smoothing_radius: f32,
+    pub dim: usize,
+    /// Only pair particles of the same material
+    pub same_material: bool,
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Calls `pair(i, j, r_ij, grad_i W_ij)` for every pair of interacting
+/// particles within the support.
+fn for_each_pair(
+    particles: &OperatorParticles,
+    neighborhood: &Neighborhood,
+    mut pair: impl FnMut(usize, usize, [f32; 3], [f32; 3]),
+) {
+    let smoothing_radius = neighborhood.smoothing_radius;
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+
+    for (i, neighbor_list) in neighborhood.neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let material_i = &neighborhood.materials[particles.material[i]];
+            let material_j = &neighborhood.materials[particles.material[j]];
+            if !material_i.interacts() || !material_j.interacts() || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+            if neighborhood.same_material && particles.material[i] != particles.material[j] {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dot(dr, dr);
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad = dr.map(|c| c * d_kernel_nd(d as f64, inv_h, neighborhood.dim) as f32 / d);
+            pair(i, j, dr, grad);
+        }
+    }
+}
+
+fn mass(particles: &OperatorParticles, neighborhood: &Neighborhood, i: usize) -> f32 {
+    neighborhood.materials[particles.material[i]].mass
+}
+
+/// Kernel interpolation `<f>_i = sum_j V_j f_j W_ij`, including the particle
+/// itself.
+pub fn interpolate(particles: &OperatorParticles, neighborhood: &Neighborhood, field: &[f32], result: &mut [f32]) {
+    let inv_h = 1.0 / neighborhood.smoothing_radius as f64;
+    let dim = neighborhood.dim;
+    let rho = particles.rho;
+    let volume = |i: usize| mass(particles, neighborhood, i) / rho[i];
+    let interacts = |i: usize| neighborhood.materials[particles.material[i]].interacts() && rho[i] > 0.0;
+
+    let self_weight = kernel_nd(0.0, inv_h, dim) as f32;
+    for (i, result) in result.iter_mut().enumerate() {
+        *result = if interacts(i) { volume(i) * field[i] * self_weight } else { 0.0 };
+    }
+    for_each_pair(particles, neighborhood, |i, j, dr, _| {
+        let w = kernel_nd(dot(dr, dr).sqrt() as f64, inv_h, dim) as f32;
+        result[i] += volume(j) * field[j] * w;
+        result[j] += volume(i) * field[i] * w;
+    });
+}
+
+/// Gradient of a scalar field.
+pub fn gradient(
+    particles: &OperatorParticles,
+    neighborhood: &Neighborhood,
+    formulation: Formulation,
+    field: &[f32],
+    result: &mut [[f32; 3]],
+) {
+    let rho = particles.rho;
+    result.fill([0.0; 3]);
+    for_each_pair(particles, neighborhood, |i, j, _, grad| {
+        let (m_i, m_j) = (mass(particles, neighborhood, i), mass(particles, neighborhood, j));
+        // grad_j W_ji = -grad_i W_ij
+        let (scale_i, scale_j) = match formulation {
+            Formulation::Difference => {
+                let difference = field[j] - field[i];
+                (m_j / rho[j] * difference, m_i / rho[i] * difference)
+            }
+            Formulation::Symmetric => {
+                let sum = field[i] / (rho[i] * rho[i]) + field[j] / (rho[j] * rho[j]);
+                (rho[i] * m_j * sum, -rho[j] * m_i * sum)
+            }
+            Formulation::Summation => (m_j / rho[j] * field[j], -m_i / rho[i] * field[i]),
+        };
+        for a in 0..3 {
+            result[i][a] += scale_i * grad[a];
+            result[j][a] += scale_j * grad[a];
+        }
+    });
+}
+
+/// Divergence of a vector field.
+pub fn divergence(
+    particles: &OperatorParticles,
+    neighborhood: &Neighborhood,
+    formulation: Formulation,
+    field: [&[f32]; 3],
+    result: &mut [f32],
+) {
+    let rho = particles.rho;
+    let value = |i: usize| [field[0][i], field[1][i], field[2][i]];
+    result.fill(0.0);
+    for_each_pair(particles, neighborhood, |i, j, _, grad| {
+        let (m_i, m_j) = (mass(particles, neighborhood, i), mass(particles, neighborhood, j));
+        let (f_i, f_j) = (value(i), value(j));
+        match formulation {
+            Formulation::Difference => {
+                let flux = dot([f_j[0] - f_i[0], f_j[1] - f_i[1], f_j[2] - f_i[2]], grad);
+                result[i] += m_j / rho[j] * flux;
+                result[j] += m_i / rho[i] * flux;
+            }
+            Formulation::Symmetric => {
+                let (rho_i_sq, rho_j_sq) = (rho[i] * rho[i], rho[j] * rho[j]);
+                let sum: [f32; 3] = std::array::from_fn(|a| f_i[a] / rho_i_sq + f_j[a] / rho_j_sq);
+                let flux = dot(sum, grad);
+                result[i] += rho[i] * m_j * flux;
+                result[j] -= rho[j] * m_i * flux;
+            }
+            Formulation::Summation => {
+                result[i] += m_j / rho[j] * dot(f_j, grad);
+                result[j] -= m_i / rho[i] * dot(f_i, grad);
+            }
+        }
+    });
+}
+
+/// Curl of a vector field; in 2D only the z component is nonzero.
+pub fn curl(
+    particles: &OperatorParticles,
+    neighborhood: &Neighborhood,
+    formulation: Formulation,
+    field: [&[f32]; 3],
+    result: &mut [[f32; 3]],
+) {
+    let rho = particles.rho;
+    let value = |i: usize| [field[0][i], field[1][i], field[2][i]];
+    result.fill([0.0; 3]);
+    for_each_pair(particles, neighborhood, |i, j, _, grad| {
+        let (m_i, m_j) = (mass(particles, neighborhood, i), mass(particles, neighborhood, j));
+        let (f_i, f_j) = (value(i), value(j));
+        let (rotation_i, rotation_j) = match formulation {
+            Formulation::Difference => {
+                let rotation = cross(grad, [f_j[0] - f_i[0], f_j[1] - f_i[1], f_j[2] - f_i[2]]);
+                (rotation.map(|c| m_j / rho[j] * c), rotation.map(|c| m_i / rho[i] * c))
+            }
+            Formulation::Symmetric => {
+                let (rho_i_sq, rho_j_sq) = (rho[i] * rho[i], rho[j] * rho[j]);
+                let rotation = cross(grad, std::array::from_fn(|a| f_i[a] / rho_i_sq + f_j[a] / rho_j_sq));
+                (rotation.map(|c| rho[i] * m_j * c), rotation.map(|c| -rho[j] * m_i * c))
+            }
+            Formulation::Summation => {
+                let (rotation_i, rotation_j) = (cross(grad, f_j), cross(grad, f_i));
+                (rotation_i.map(|c| m_j / rho[j] * c), rotation_j.map(|c| -m_i / rho[i] * c))
+            }
+        };
+        for a in 0..3 {
+            result[i][a] += rotation_i[a];
+            result[j][a] += rotation_j[a];
+        }
+    });
+}
+
+/// Laplacian of a scalar field in the form of Brookshaw (1985),
+/// `2 sum_j V_j (f_i - f_j) r_ij . grad_i W_ij / |r_ij|^2`, which only needs
+/// the first kernel derivative and recovers quadratic fields away from the
+/// free surface.
+pub fn laplacian(particles: &OperatorParticles, neighborhood: &Neighborhood, field: &[f32], result: &mut [f32]) {
+    let rho = particles.rho;
+    result.fill(0.0);
+    for_each_pair(particles, neighborhood, |i, j, dr, grad| {
+        let (m_i, m_j) = (mass(particles, neighborhood, i), mass(particles, neighborhood, j));
+        // r_ji . grad_j W_ji = r_ij . grad_i W_ij and f_j - f_i = -(f_i - f_j)
+        let term = 2.0 * (field[i] - field[j]) * dot(dr, grad) / dot(dr, dr);
+        result[i] += m_j / rho[j] * term;
+        result[j] -= m_i / rho[i] * term;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{half_neighbor_lists, square_lattice, water, SMOOTHING_RADIUS, SPACING};
+
+    const SIDE: usize = 16;
+
+    /// Square lattice of water at rest density with a half neighbor list.
+    struct Lattice {
+        position: [Vec<f32>; 3],
+        rho: Vec<f32>,
+        material: Vec<usize>,
+        materials: [Material; 2],
+        neighbors: Vec<Vec<usize>>,
+        same_material: bool,
+    }
+
+    impl Lattice {
+        fn new() -> Self {
+            let position = square_lattice(SIDE);
+            let n = SIDE * SIDE;
+            let neighbors = half_neighbor_lists(&position);
+            let materials = [water(), water()];
+            Lattice { position, rho: vec![1.0; n], material: vec![0; n], materials, neighbors, same_material: false }
+        }
+
+        fn len(&self) -> usize {
+            self.rho.len()
+        }
+
+        fn particles(&self) -> OperatorParticles<'_> {
+            OperatorParticles {
+                position: [&self.position[0], &self.position[1], &self.position[2]],
+                rho: &self.rho,
+                material: &self.material,
+            }
+        }
+
+        fn neighborhood(&self) -> Neighborhood<'_> {
+            Neighborhood {
+                materials: &self.materials,
+                neighbors: &self.neighbors,
+                smoothing_radius: SMOOTHING_RADIUS,
+                dim: 2,
+                same_material: self.same_material,
+            }
+        }
+
+        fn scalar(&self, f: impl Fn(f32, f32) -> f32) -> Vec<f32> {
+            (0..self.len()).map(|i| f(self.position[0][i], self.position[1][i])).collect()
+        }
+
+        fn vector(&self, f: impl Fn(f32, f32) -> [f32; 2]) -> [Vec<f32>; 3] {
+            let values: Vec<[f32; 2]> = (0..self.len()).map(|i| f(self.position[0][i], self.position[1][i])).collect();
+            [values.iter().map(|v| v[0]).collect(), values.iter().map(|v| v[1]).collect(), vec![0.0; self.len()]]
+        }
+
+        /// Particles whose kernel support lies inside the lattice.
+        fn interior(&self) -> impl Iterator<Item = usize> + '_ {
+            let margin = SMOOTHING_RADIUS + 0.01;
+            let far = (SIDE - 1) as f32 * SPACING - margin;
+            (0..self.len()).filter(move |&i| {
+                [self.position[0][i], self.position[1][i]].iter().all(|&c| c > margin && c < far)
+            })
+        }
+    }
+
+    const FORMULATIONS: [Formulation; 3] = [Formulation::Difference, Formulation::Symmetric, Formulation::Summation];
+
+    #[test]
+    fn interpolation_reproduces_linear_field() {
+        let lattice = Lattice::new();
+        let field = lattice.scalar(|x, y| 1.0 + 2.0 * x - y);
+        let mut result = vec![0.0; lattice.len()];
+        interpolate(&lattice.particles(), &lattice.neighborhood(), &field, &mut result);
+        for i in lattice.interior() {
+            assert!((result[i] - field[i]).abs() < 0.02 * field[i].abs().max(1.0), "particle {i}: {} {}", result[i], field[i]);
+        }
+    }
+
+    #[test]
+    fn gradient_of_linear_field() {
+        let lattice = Lattice::new();
+        let field = lattice.scalar(|x, y| 1.0 + 2.0 * x - 3.0 * y);
+        for formulation in FORMULATIONS {
+            let mut result = vec![[0.0; 3]; lattice.len()];
+            gradient(&lattice.particles(), &lattice.neighborhood(), formulation, &field, &mut result);
+            for i in lattice.interior() {
+                assert!((result[i][0] - 2.0).abs() < 0.05, "{formulation:?} particle {i}: {:?}", result[i]);
+                assert!((result[i][1] + 3.0).abs() < 0.05, "{formulation:?} particle {i}: {:?}", result[i]);
+                assert_eq!(result[i][2], 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn divergence_of_linear_field() {
+        let lattice = Lattice::new();
+        let field = lattice.vector(|x, y| [x + 0.5, 2.0 * y - x]);
+        for formulation in FORMULATIONS {
+            let mut result = vec![0.0; lattice.len()];
+            let [u, v, w] = &field;
+            divergence(&lattice.particles(), &lattice.neighborhood(), formulation, [u, v, w], &mut result);
+            for i in lattice.interior() {
+                assert!((result[i] - 3.0).abs() < 0.05, "{formulation:?} particle {i}: {}", result[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn curl_of_rotation() {
+        let lattice = Lattice::new();
+        let field = lattice.vector(|x, y| [-y, x + 1.0]);
+        for formulation in FORMULATIONS {
+            let mut result = vec![[0.0; 3]; lattice.len()];
+            let [u, v, w] = &field;
+            curl(&lattice.particles(), &lattice.neighborhood(), formulation, [u, v, w], &mut result);
+            for i in lattice.interior() {
+                assert!((result[i][2] - 2.0).abs() < 0.05, "{formulation:?} particle {i}: {:?}", result[i]);
+                assert_eq!(&result[i][..2], &[0.0, 0.0]);
+            }
+        }
+    }
+
+    #[test]
+    fn laplacian_of_quadratic_field() {
+        let lattice = Lattice::new();
+        let field = lattice.scalar(|x, y| x * x + 2.0 * y * y - x * y);
+        let mut result = vec![0.0; lattice.len()];
+        laplacian(&lattice.particles(), &lattice.neighborhood(), &field, &mut result);
+        for i in lattice.interior() {
+            assert!((result[i] - 6.0).abs() < 0.1, "particle {i}: {}", result[i]);
+        }
+    }
+
+    #[test]
+    fn symmetric_gradient_conserves_momentum() {
+        // Forces -m_i grad p_i / rho_i cancel in total, also at the edges and
+        // for an uneven density
+        let mut lattice = Lattice::new();
+        lattice.rho = lattice.scalar(|x, y| 1.0 + 0.1 * (7.0 * x).sin() * y);
+        let pressure = lattice.scalar(|x, y| (x - y).powi(2) + 0.3 * (5.0 * y).cos());
+        let mut result = vec![[0.0; 3]; lattice.len()];
+        gradient(&lattice.particles(), &lattice.neighborhood(), Formulation::Symmetric, &pressure, &mut result);
+        let mass = lattice.materials[0].mass;
+        for a in 0..2 {
+            let total: f32 = result.iter().zip(&lattice.rho).map(|(g, rho)| mass * g[a] / rho).sum();
+            let scale: f32 = result.iter().zip(&lattice.rho).map(|(g, rho)| (mass * g[a] / rho).abs()).sum();
+            assert!(total.abs() < 1e-5 * scale, "{total} {scale}");
+        }
+    }
+
+    #[test]
+    fn summation_gradient_of_unit_field_leans_into_the_fluid() {
+        let lattice = Lattice::new();
+        let mut result = vec![[0.0; 3]; lattice.len()];
+        gradient(&lattice.particles(), &lattice.neighborhood(), Formulation::Summation, &vec![1.0; lattice.len()], &mut result);
+        for i in lattice.interior() {
+            assert!(result[i].iter().all(|g| g.abs() < 1e-4), "particle {i}: {:?}", result[i]);
+        }
+        // Left edge, halfway up: the gradient points right, into the lattice
+        let edge = result[SIDE / 2];
+        assert!(edge[0] > 0.5 && edge[1].abs() < 1e-4, "{edge:?}");
+    }
+
+    #[test]
+    fn same_material_pairs_ignore_the_interface() {
+        // Two materials meeting at x = 0.75 with a jump in the field
+        let mut lattice = Lattice::new();
+        lattice.material = lattice.position[0].iter().map(|&x| usize::from(x > 0.75)).collect();
+        let field: Vec<f32> = lattice.material.iter().map(|&m| m as f32).collect();
+        for same_material in [false, true] {
+            lattice.same_material = same_material;
+            let mut result = vec![[0.0; 3]; lattice.len()];
+            gradient(&lattice.particles(), &lattice.neighborhood(), Formulation::Difference, &field, &mut result);
+            let largest = result.iter().map(|g| g[0].abs()).fold(0.0, f32::max);
+            assert_eq!(largest == 0.0, same_material, "{largest}");
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use crate::kernel::d_kernel_nd;
+use crate::material::Material;
+use crate::elasticity::Matrix3;
+
+// Shear rate below which the power law is evaluated, so the viscosity of a
+// shear-thinning fluid at rest stays finite
+const MIN_SHEAR_RATE: f32 = 1e-3;
+
+/// Dependence of the dynamic viscosity on the local shear rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViscosityModel {
+    /// Constant viscosity, taken from `Material::viscosity`
+    Newtonian,
+    /// `eta = K shear_rate^(n - 1)`; shear thinning for `n < 1`
+    PowerLaw { consistency: f32, flow_index: f32 },
+    /// `eta = eta_inf + (eta_0 - eta_inf) (1 + (lambda shear_rate)^2)^((n - 1) / 2)`
+    Carreau { zero_shear_viscosity: f32, infinite_shear_viscosity: f32, relaxation_time: f32, flow_index: f32 },
+    /// Bingham plastic with Papanastasiou's regularization,
+    /// `eta = mu_p + tau_y (1 - exp(-m shear_rate)) / shear_rate`
+    Bingham { yield_stress: f32, plastic_viscosity: f32, regularization: f32 },
+}
+
+impl ViscosityModel {
+    pub fn is_newtonian(&self) -> bool {
+        matches!(self, ViscosityModel::Newtonian)
+    }
+
+    /// Effective viscosity at `shear_rate`; `newtonian` is returned for the
+    /// Newtonian model.
+    pub fn viscosity(&self, newtonian: f32, shear_rate: f32) -> f32 {
+        match *self {
+            ViscosityModel::Newtonian => newtonian,
+            ViscosityModel::PowerLaw { consistency, flow_index } => {
+                consistency * shear_rate.max(MIN_SHEAR_RATE).powf(flow_index - 1.0)
+            }
+            ViscosityModel::Carreau { zero_shear_viscosity, infinite_shear_viscosity, relaxation_time, flow_index } => {
+                let thinning = (1.0 + (relaxation_time * shear_rate).powi(2)).powf(0.5 * (flow_index - 1.0));
+                infinite_shear_viscosity + (zero_shear_viscosity - infinite_shear_viscosity) * thinning
+            }
+            ViscosityModel::Bingham { yield_stress, plastic_viscosity, regularization } => {
+                // (1 - exp(-m s)) / s tends to m as s goes to zero
+                let yielded = if shear_rate > f32::EPSILON {
+                    -(-regularization * shear_rate).exp_m1() / shear_rate
+                } else {
+                    regularization
+                };
+                plastic_viscosity + yield_stress * yielded
+            }
+        }
+    }
+}
+
+/// Borrowed per-particle fields read by the viscous terms.
+pub struct FlowParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub velocity: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// SPH velocity gradient `grad v_i = sum_j V_j (v_j - v_i) (x) grad_i W_ij`,
+/// indexed `[component][direction]`.
+pub fn velocity_gradients(
+    particles: &FlowParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    gradient: &mut [Matrix3],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let [vx, vy, vz] = particles.velocity;
+    let rho = particles.rho;
+
+    gradient.fill([[0.0; 3]; 3]);
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let material_i = &materials[particles.material[i]];
+            let material_j = &materials[particles.material[j]];
+            if !material_i.interacts() || !material_j.interacts() || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad_scale = d_kernel_nd(d as f64, inv_h, dim) as f32 / d;
+            let dv = [vx[j] - vx[i], vy[j] - vy[i], vz[j] - vz[i]];
+            let volume_i = material_i.mass / rho[i];
+            let volume_j = material_j.mass / rho[j];
+
+            // grad_j W_ji = -grad_i W_ij and v_i - v_j = -(v_j - v_i), so
+            // both particles see the same product
+            for a in 0..3 {
+                for b in 0..3 {
+                    let term = dv[a] * grad_scale * dr[b];
+                    gradient[i][a][b] += volume_j * term;
+                    gradient[j][a][b] += volume_i * term;
+                }
+            }
+        }
+    }
+}
+
+/// Shear rate `sqrt(2 D:D)` from the SPH velocity gradient.
+pub fn shear_rates(
+    particles: &FlowParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    shear_rate: &mut [f32],
+) {
+    let mut gradient = vec![[[0.0_f32; 3]; 3]; shear_rate.len()];
+    velocity_gradients(particles, materials, neighbors, smoothing_radius, dim, &mut gradient);
+
+    for (rate, l) in shear_rate.iter_mut().zip(&gradient) {
+        let double_contraction: f32 = (0..9)
+            .map(|k| {
+                let strain_rate = 0.5 * (l[k / 3][k % 3] + l[k % 3][k / 3]);
+                strain_rate * strain_rate
+            })
+            .sum();
+        *rate = (2.0 * double_contraction).sqrt();
+    }
+}
+
+/// Per-particle viscosity from each material's model at its shear rate.
+pub fn effective_viscosities(materials: &[Material], material: &[usize], shear_rate: &[f32], viscosity: &mut [f32]) {
+    for ((eta, &k), &rate) in viscosity.iter_mut().zip(material).zip(shear_rate) {
+        let material = &materials[k];
+        *eta = material.rheology.viscosity(material.viscosity, rate);
+    }
+}
+
+/// Adds viscous accelerations in the particle-volume form of Hu & Adams
+/// (2006), with the harmonic mean of the two particles' viscosities so the
+/// stress stays continuous between materials.
+pub fn accumulate_viscous_forces(
+    particles: &FlowParticles,
+    materials: &[Material],
+    viscosity: &[f32],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    accel: [&mut [f32]; 3],
+) {
+    let [ax, ay, az] = accel;
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let [vx, vy, vz] = particles.velocity;
+    let rho = particles.rho;
+
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let viscosity_sum = viscosity[i] + viscosity[j];
+            if viscosity_sum <= 0.0 || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+            let material_i = &materials[particles.material[i]];
+            let material_j = &materials[particles.material[j]];
+            if !material_i.interacts() || !material_j.interacts() {
+                continue;
+            }
+
+            let dx = x[i] - x[j];
+            let dy = y[i] - y[j];
+            let dz = z[i] - z[j];
+            let r2 = dx * dx + dy * dy + dz * dz;
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad_scale = d_kernel_nd(d as f64, inv_h, dim) as f32 / d;
+            let volume_i = material_i.mass / rho[i];
+            let volume_j = material_j.mass / rho[j];
+            let mean_viscosity = 2.0 * viscosity[i] * viscosity[j] / viscosity_sum;
+
+            // Force on i; dW/dr is negative, so it opposes the relative velocity
+            let friction = mean_viscosity * (volume_i * volume_i + volume_j * volume_j) * grad_scale;
+            let fx = friction * (vx[i] - vx[j]);
+            let fy = friction * (vy[i] - vy[j]);
+            let fz = friction * (vz[i] - vz[j]);
+
+            ax[i] += fx / material_i.mass;
+            ay[i] += fy / material_i.mass;
+            az[i] += fz / material_i.mass;
+            ax[j] -= fx / material_j.mass;
+            ay[j] -= fy / material_j.mass;
+            az[j] -= fz / material_j.mass;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kd_tree::KdTree;
+    use crate::neighbor_search::NeighborSearch;
+    use crate::test_support::water;
+
+    const SIDE: usize = 30;
+    const SPACING: f32 = 0.1;
+
+    fn fluid(viscosity: f32) -> Material {
+        Material { mass: SPACING * SPACING, viscosity, ..water() }
+    }
+
+    /// Unit density lattice with velocity field `v_x = profile(y)` and the
+    /// indices of the particles whose kernel support lies inside it.
+    struct Shear {
+        position: [Vec<f32>; 3],
+        velocity: [Vec<f32>; 3],
+        rho: Vec<f32>,
+        material: Vec<usize>,
+        neighbors: Vec<Vec<usize>>,
+        interior: Vec<usize>,
+    }
+
+    impl Shear {
+        fn new(smoothing_radius: f32, profile: impl Fn(f32) -> f32) -> Self {
+            let mut px = Vec::new();
+            let mut py = Vec::new();
+            let mut interior = Vec::new();
+            let extent = (SIDE - 1) as f32 * SPACING;
+            for i in 0..SIDE {
+                for j in 0..SIDE {
+                    let (x, y) = (i as f32 * SPACING, j as f32 * SPACING);
+                    if [x, y].iter().all(|&c| c > smoothing_radius && c < extent - smoothing_radius) {
+                        interior.push(px.len());
+                    }
+                    px.push(x);
+                    py.push(y);
+                }
+            }
+            let n = px.len();
+            let vx = py.iter().map(|&y| profile(y)).collect();
+            let pz = vec![0.0; n];
+
+            let mut neighbors = vec![Vec::new(); n];
+            KdTree::default().find_neighbors([&px, &py, &pz], &vec![smoothing_radius; n], &mut neighbors);
+
+            // Summation density, which the lattice spacing makes unity in the interior
+            let rho = vec![1.0; n];
+            Shear {
+                position: [px, py, pz],
+                velocity: [vx, vec![0.0; n], vec![0.0; n]],
+                rho,
+                material: vec![0; n],
+                neighbors,
+                interior,
+            }
+        }
+
+        fn particles(&self) -> FlowParticles<'_> {
+            FlowParticles {
+                position: [&self.position[0], &self.position[1], &self.position[2]],
+                velocity: [&self.velocity[0], &self.velocity[1], &self.velocity[2]],
+                rho: &self.rho,
+                material: &self.material,
+            }
+        }
+    }
+
+    #[test]
+    fn models_match_closed_forms() {
+        let power_law = ViscosityModel::PowerLaw { consistency: 2.0, flow_index: 0.5 };
+        assert!((power_law.viscosity(0.0, 4.0) - 1.0).abs() < 1e-6);
+        assert!(power_law.viscosity(0.0, 0.0).is_finite());
+
+        let carreau = ViscosityModel::Carreau {
+            zero_shear_viscosity: 10.0,
+            infinite_shear_viscosity: 0.1,
+            relaxation_time: 1.0,
+            flow_index: 0.3,
+        };
+        assert!((carreau.viscosity(0.0, 0.0) - 10.0).abs() < 1e-6);
+        assert!(carreau.viscosity(0.0, 1e4) < 0.2);
+        assert!(carreau.viscosity(0.0, 1.0) < carreau.viscosity(0.0, 0.5));
+
+        let bingham = ViscosityModel::Bingham { yield_stress: 5.0, plastic_viscosity: 0.5, regularization: 100.0 };
+        // Stiff below the yield point, plastic flow far above it
+        assert!((bingham.viscosity(0.0, 0.0) - 500.5).abs() < 1e-3);
+        let fast = 1e3;
+        assert!((bingham.viscosity(0.0, fast) - (0.5 + 5.0 / fast)).abs() < 1e-5);
+
+        assert_eq!(ViscosityModel::Newtonian.viscosity(0.3, 7.0), 0.3);
+    }
+
+    #[test]
+    fn shear_rate_of_simple_shear() {
+        let smoothing_radius = 3.0 * SPACING;
+        let rate = 2.5;
+        let shear = Shear::new(smoothing_radius, |y| rate * y);
+        let mut rho = vec![0.0; shear.rho.len()];
+        crate::gas_dynamics::summation_density(
+            [&shear.position[0], &shear.position[1], &shear.position[2]],
+            &shear.neighbors,
+            &vec![SPACING * SPACING; shear.rho.len()],
+            smoothing_radius,
+            2,
+            &mut rho,
+        );
+        let shear = Shear { rho, ..shear };
+
+        let mut shear_rate = vec![0.0; shear.rho.len()];
+        shear_rates(&shear.particles(), &[fluid(0.0)], &shear.neighbors, smoothing_radius, 2, &mut shear_rate);
+
+        for &i in &shear.interior {
+            assert!((shear_rate[i] - rate).abs() < 0.02 * rate, "particle {}: {}", i, shear_rate[i]);
+        }
+    }
+
+    #[test]
+    fn viscous_force_matches_laplacian() {
+        let smoothing_radius = 3.0 * SPACING;
+        let viscosity = 0.4;
+        let materials = [fluid(viscosity)];
+
+        // Linear shear carries no net viscous force, a parabola a uniform one
+        let profiles: [fn(f32) -> f32; 2] = [|y| 3.0 * y, |y| y * y];
+        for (profile, expected) in profiles.into_iter().zip([0.0, 2.0 * viscosity]) {
+            let shear = Shear::new(smoothing_radius, profile);
+            let n = shear.rho.len();
+            let mut accel = [vec![0.0; n], vec![0.0; n], vec![0.0; n]];
+            let [ax, ay, az] = &mut accel;
+            let eta = vec![viscosity; n];
+            accumulate_viscous_forces(&shear.particles(), &materials, &eta, &shear.neighbors, smoothing_radius, 2, [ax, ay, az]);
+
+            for &i in &shear.interior {
+                assert!((accel[0][i] - expected).abs() < 0.05, "particle {}: {}", i, accel[0][i]);
+                assert!(accel[1][i].abs() < 1e-3);
+            }
+        }
+    }
+}
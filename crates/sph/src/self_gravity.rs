@@ -0,0 +1,273 @@
+const LEAF_SIZE: usize = 8;
+// Guards against endless subdivision of coincident particles
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct GravityParameters {
+    pub gravitational_constant: f32,
+    /// Cells seen under an angle `size / distance` below this are treated as
+    /// point masses; zero reduces to direct summation.
+    pub opening_angle: f32,
+    /// Plummer softening length
+    pub softening: f32,
+}
+
+#[derive(Debug, Clone)]
+struct OctreeNode {
+    start: usize,
+    end: usize,
+    corner: [f32; 3],
+    size: f32,
+    mass: f32,
+    center_of_mass: [f32; 3],
+    children: Option<(usize, usize)>,
+}
+
+/// Octree over particle positions storing the mass and center of mass of
+/// every cell, for Barnes & Hut (1986) gravity.
+#[derive(Debug, Clone, Default)]
+pub struct BarnesHutTree {
+    indices: Vec<usize>,
+    nodes: Vec<OctreeNode>,
+}
+
+impl BarnesHutTree {
+    pub fn build(&mut self, positions: [&[f32]; 3], masses: &[f32]) {
+        let n = masses.len();
+        self.indices.clear();
+        self.indices.extend(0..n);
+        self.nodes.clear();
+
+        if n == 0 {
+            return;
+        }
+
+        let mut lo = [f32::INFINITY; 3];
+        let mut hi = [f32::NEG_INFINITY; 3];
+        for d in 0..3 {
+            for &c in &positions[d][..n] {
+                lo[d] = lo[d].min(c);
+                hi[d] = hi[d].max(c);
+            }
+        }
+        let size = (0..3).map(|d| hi[d] - lo[d]).fold(0.0_f32, f32::max);
+
+        self.nodes.push(OctreeNode { start: 0, end: n, corner: lo, size, mass: 0.0, center_of_mass: [0.0; 3], children: None });
+        self.build_node(0, positions, masses, 0);
+    }
+
+    fn build_node(&mut self, node: usize, positions: [&[f32]; 3], masses: &[f32], depth: usize) {
+        let OctreeNode { start, end, corner, size, .. } = self.nodes[node];
+
+        let mut mass = 0.0;
+        let mut moment = [0.0; 3];
+        for &i in &self.indices[start..end] {
+            mass += masses[i];
+            for d in 0..3 {
+                moment[d] += masses[i] * positions[d][i];
+            }
+        }
+        self.nodes[node].mass = mass;
+        self.nodes[node].center_of_mass = if mass > 0.0 {
+            moment.map(|m| m / mass)
+        } else {
+            corner.map(|c| c + 0.5 * size)
+        };
+
+        if end - start <= LEAF_SIZE || depth == MAX_DEPTH {
+            return;
+        }
+
+        // Sort the particles into octants, ordered by their 3-bit index
+        let half = 0.5 * size;
+        let octant = |i: usize| {
+            (0..3).fold(0, |bits, d| bits | (((positions[d][i] >= corner[d] + half) as usize) << d))
+        };
+        self.indices[start..end].sort_unstable_by_key(|&i| octant(i));
+
+        let first_child = self.nodes.len();
+        let mut bounds = start;
+        for o in 0..8 {
+            let count = self.indices[bounds..end].iter().take_while(|&&i| octant(i) == o).count();
+            if count > 0 {
+                self.nodes.push(OctreeNode {
+                    start: bounds,
+                    end: bounds + count,
+                    corner: std::array::from_fn(|d| corner[d] + if o >> d & 1 == 1 { half } else { 0.0 }),
+                    size: half,
+                    mass: 0.0,
+                    center_of_mass: [0.0; 3],
+                    children: None,
+                });
+            }
+            bounds += count;
+        }
+        let last_child = self.nodes.len();
+        self.nodes[node].children = Some((first_child, last_child));
+
+        for child in first_child..last_child {
+            self.build_node(child, positions, masses, depth + 1);
+        }
+    }
+
+    /// Gravitational acceleration at `point`, skipping particle `exclude`.
+    pub fn acceleration(
+        &self,
+        positions: [&[f32]; 3],
+        masses: &[f32],
+        point: [f32; 3],
+        exclude: Option<usize>,
+        params: &GravityParameters,
+    ) -> [f32; 3] {
+        let mut accel = [0.0; 3];
+        if self.nodes.is_empty() {
+            return accel;
+        }
+
+        let eps2 = params.softening * params.softening;
+        let theta2 = params.opening_angle * params.opening_angle;
+        let mut pull = |mass: f32, source: [f32; 3]| {
+            let dr: [f32; 3] = std::array::from_fn(|d| source[d] - point[d]);
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2] + eps2;
+            if r2 > 0.0 {
+                let factor = params.gravitational_constant * mass / (r2 * r2.sqrt());
+                for d in 0..3 {
+                    accel[d] += factor * dr[d];
+                }
+            }
+        };
+
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let n = &self.nodes[node];
+            let dr: [f32; 3] = std::array::from_fn(|d| n.center_of_mass[d] - point[d]);
+            let d2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+
+            // Cells containing the point itself are always opened
+            let contains_point = (0..3).all(|d| point[d] >= n.corner[d] && point[d] <= n.corner[d] + n.size);
+            if !contains_point && n.size * n.size < theta2 * d2 {
+                pull(n.mass, n.center_of_mass);
+                continue;
+            }
+
+            match n.children {
+                Some((first, last)) => stack.extend(first..last),
+                None => {
+                    for &j in &self.indices[n.start..n.end] {
+                        if Some(j) != exclude {
+                            pull(masses[j], [positions[0][j], positions[1][j], positions[2][j]]);
+                        }
+                    }
+                }
+            }
+        }
+
+        accel
+    }
+}
+
+/// Builds the tree and adds the self-gravity of all particles to `accel`.
+pub fn add_self_gravity(
+    tree: &mut BarnesHutTree,
+    positions: [&[f32]; 3],
+    masses: &[f32],
+    params: &GravityParameters,
+    accel: [&mut [f32]; 3],
+) {
+    tree.build(positions, masses);
+
+    let [ax, ay, az] = accel;
+    for i in 0..masses.len() {
+        let point = [positions[0][i], positions[1][i], positions[2][i]];
+        let a = tree.acceleration(positions, masses, point, Some(i), params);
+        ax[i] += a[0];
+        ay[i] += a[1];
+        az[i] += a[2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{random_numbers, scattered_points};
+
+    fn direct_sum(positions: [&[f32]; 3], masses: &[f32], params: &GravityParameters) -> Vec<[f32; 3]> {
+        (0..masses.len())
+            .map(|i| {
+                let mut a = [0.0_f64; 3];
+                for j in 0..masses.len() {
+                    if i == j {
+                        continue;
+                    }
+                    let dr: [f64; 3] = std::array::from_fn(|d| (positions[d][j] - positions[d][i]) as f64);
+                    let r2 = dr.iter().map(|x| x * x).sum::<f64>() + (params.softening * params.softening) as f64;
+                    let factor = (params.gravitational_constant * masses[j]) as f64 / (r2 * r2.sqrt());
+                    for d in 0..3 {
+                        a[d] += factor * dr[d];
+                    }
+                }
+                a.map(|x| x as f32)
+            })
+            .collect()
+    }
+
+    /// RMS force error relative to the RMS force
+    fn relative_error(tree_accel: [&[f32]; 3], exact: &[[f32; 3]]) -> f32 {
+        let mut diff = 0.0_f32;
+        let mut norm = 0.0_f32;
+        for (i, e) in exact.iter().enumerate() {
+            diff += (0..3).map(|d| (tree_accel[d][i] - e[d]).powi(2)).sum::<f32>();
+            norm += e.iter().map(|x| x * x).sum::<f32>();
+        }
+        (diff / norm).sqrt()
+    }
+
+    fn tree_accelerations(opening_angle: f32) -> f32 {
+        let (px, py, pz) = scattered_points(400, 11, [[-1.0, 1.0], [-1.0, 1.0], [-0.5, 0.5]]);
+        let mut next = random_numbers(12);
+        let masses: Vec<f32> = (0..px.len()).map(|_| 0.5 + next()).collect();
+        let positions = [&px[..], &py[..], &pz[..]];
+        let params = GravityParameters { gravitational_constant: 2.0, opening_angle, softening: 0.01 };
+
+        let mut ax = vec![0.0; masses.len()];
+        let mut ay = vec![0.0; masses.len()];
+        let mut az = vec![0.0; masses.len()];
+        add_self_gravity(&mut BarnesHutTree::default(), positions, &masses, &params, [&mut ax, &mut ay, &mut az]);
+
+        relative_error([&ax, &ay, &az], &direct_sum(positions, &masses, &params))
+    }
+
+    #[test]
+    fn zero_opening_angle_is_direct_summation() {
+        let error = tree_accelerations(0.0);
+        assert!(error < 1e-4, "error {}", error);
+    }
+
+    #[test]
+    fn tree_approximates_direct_summation() {
+        let error = tree_accelerations(0.5);
+        assert!(error < 0.01, "error {}", error);
+    }
+
+    #[test]
+    fn softened_pair_attraction() {
+        let px = [0.0, 3.0];
+        let py = [0.0, 4.0];
+        let pz = [0.0, 0.0];
+        let masses = [2.0, 6.0];
+        let params = GravityParameters { gravitational_constant: 1.5, opening_angle: 0.5, softening: 1.0 };
+
+        let mut ax = [0.0; 2];
+        let mut ay = [0.0; 2];
+        let mut az = [0.0; 2];
+        add_self_gravity(&mut BarnesHutTree::default(), [&px, &py, &pz], &masses, &params, [&mut ax, &mut ay, &mut az]);
+
+        // |a| = G m r / (r^2 + eps^2)^(3/2) with r = 5
+        let scale = 1.5 * 5.0 / 26.0_f32.powf(1.5);
+        assert!((ax[0] - 6.0 * scale * 0.6).abs() < 1e-5);
+        assert!((ay[0] - 6.0 * scale * 0.8).abs() < 1e-5);
+        // Equal and opposite forces
+        assert!((2.0 * ax[0] + 6.0 * ax[1]).abs() < 1e-5);
+        assert!((2.0 * ay[0] + 6.0 * ay[1]).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::kernel::{d_kernel_nd, kernel_nd};
+use crate::material::Material;
+
+// Tensile correction of the concentration gradient (Lind et al. 2012)
+const TENSILE_STRENGTH: f32 = 0.2;
+const TENSILE_EXPONENT: i32 = 4;
+// Largest shift per step, relative to the smoothing length
+const MAX_SHIFT: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShiftingParameters {
+    /// `A` in the diffusion coefficient `D = A h |v| dt` of Skillen et al.
+    pub coefficient: f32,
+    pub timestep: f32,
+    pub smoothing_radius: f32,
+    pub dim: usize,
+}
+
+/// Borrowed per-particle fields read by particle shifting.
+pub struct ShiftingParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub velocity: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+    /// Free-surface flags and outward normals from `detect_free_surface`
+    pub free_surface: &'a [bool],
+    pub surface_normal: &'a [[f32; 3]],
+}
+
+/// Particle shifting of Lind et al. (2012), `dr_i = -D grad C_i`, which moves
+/// fluid particles from crowded towards sparse regions along the gradient of
+/// the particle concentration
+/// `grad C_i = sum_j V_j (1 + R (W_ij / W(dx))^n) grad_i W_ij`.
+/// Scaling `D` with the distance travelled per step keeps the shift small
+/// compared to the flow. On the free surface only the part tangential to the
+/// surface is kept, so particles do not leave the fluid.
+pub fn shifting_displacements(
+    particles: &ShiftingParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    params: &ShiftingParameters,
+    displacement: &mut [[f32; 3]],
+) {
+    let smoothing_radius = params.smoothing_radius;
+    let dim = params.dim;
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let [vx, vy, vz] = particles.velocity;
+    let rho = particles.rho;
+    let n = rho.len();
+
+    let mut concentration_gradient = vec![[0.0_f32; 3]; n];
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        for &j in neighbor_list {
+            let material_i = &materials[particles.material[i]];
+            let material_j = &materials[particles.material[j]];
+            if !material_i.interacts() || !material_j.interacts() || rho[i] <= 0.0 || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad_scale = d_kernel_nd(d as f64, inv_h, dim) as f32 / d;
+            let volume_i = material_i.mass / rho[i];
+            let volume_j = material_j.mass / rho[j];
+            let spacing = (material_i.mass / material_i.rest_density).powf(1.0 / dim as f32);
+            let w_spacing = kernel_nd(spacing as f64, inv_h, dim) as f32;
+            let tensile = if w_spacing > 0.0 {
+                1.0 + TENSILE_STRENGTH * (kernel_nd(d as f64, inv_h, dim) as f32 / w_spacing).powi(TENSILE_EXPONENT)
+            } else {
+                1.0
+            };
+
+            // grad_j W_ji = -grad_i W_ij
+            for a in 0..3 {
+                concentration_gradient[i][a] += volume_j * tensile * grad_scale * dr[a];
+                concentration_gradient[j][a] -= volume_i * tensile * grad_scale * dr[a];
+            }
+        }
+    }
+
+    let h = 0.5 * smoothing_radius;
+    for i in 0..n {
+        displacement[i] = [0.0; 3];
+        if !materials[particles.material[i]].is_fluid() {
+            continue;
+        }
+
+        let speed = (vx[i] * vx[i] + vy[i] * vy[i] + vz[i] * vz[i]).sqrt();
+        let diffusion = params.coefficient * h * speed * params.timestep;
+        let mut shift = concentration_gradient[i].map(|g| -diffusion * g);
+
+        if particles.free_surface[i] {
+            let normal = particles.surface_normal[i];
+            let normal_shift: f32 = (0..3).map(|a| shift[a] * normal[a]).sum();
+            for a in 0..3 {
+                shift[a] -= normal_shift * normal[a];
+            }
+        }
+
+        let length = shift.iter().map(|s| s * s).sum::<f32>().sqrt();
+        let limit = MAX_SHIFT * h;
+        if length > limit {
+            shift = shift.map(|s| s * limit / length);
+        }
+        displacement[i] = shift;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::free_surface::{detect_free_surface, FreeSurfaceParticles};
+    use crate::test_support::{half_neighbor_lists, square_lattice, water, SMOOTHING_RADIUS, SPACING};
+
+    const SIDE: usize = 12;
+
+    /// Shifts of a square block moving with unit speed along x, after
+    /// `perturb` has displaced some of its particles.
+    fn block_shifts(perturb: impl Fn(usize, &mut [f32; 2])) -> Vec<[f32; 3]> {
+        let mut position = square_lattice(SIDE);
+        let n = SIDE * SIDE;
+        let [x, y, _] = &mut position;
+        for (k, (x, y)) in x.iter_mut().zip(y.iter_mut()).enumerate() {
+            let mut p = [*x, *y];
+            perturb(k, &mut p);
+            [*x, *y] = p;
+        }
+        let neighbors = half_neighbor_lists(&position);
+        let (rho, material) = (vec![1.0; n], vec![0; n]);
+        let position = [&position[0][..], &position[1], &position[2]];
+
+        let (mut free_surface, mut surface_normal) = (vec![false; n], vec![[0.0; 3]; n]);
+        let surface_particles = FreeSurfaceParticles { position, rho: &rho, material: &material };
+        detect_free_surface(&surface_particles, &[water()], &neighbors, SMOOTHING_RADIUS, 2, &mut free_surface, &mut surface_normal);
+
+        let velocity = [vec![1.0; n], vec![0.0; n], vec![0.0; n]];
+        let particles = ShiftingParticles {
+            position,
+            velocity: [&velocity[0], &velocity[1], &velocity[2]],
+            rho: &rho,
+            material: &material,
+            free_surface: &free_surface,
+            surface_normal: &surface_normal,
+        };
+        let params = ShiftingParameters { coefficient: 2.0, timestep: 0.01, smoothing_radius: SMOOTHING_RADIUS, dim: 2 };
+        let mut displacement = vec![[0.0; 3]; n];
+        shifting_displacements(&particles, &[water()], &neighbors, &params, &mut displacement);
+        displacement
+    }
+
+    fn index(i: usize, j: usize) -> usize {
+        i * SIDE + j
+    }
+
+    #[test]
+    fn regular_lattice_stays_put() {
+        let displacement = block_shifts(|_, _| {});
+        for i in 3..SIDE - 3 {
+            for j in 3..SIDE - 3 {
+                let shift = displacement[index(i, j)];
+                assert!(shift.iter().all(|s| s.abs() < 1e-6), "{i} {j}: {shift:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn displaced_particle_moves_back() {
+        let moved = index(6, 6);
+        let displacement = block_shifts(|k, p| {
+            if k == moved {
+                p[0] += 0.3 * SPACING;
+            }
+        });
+        let shift = displacement[moved];
+        assert!(shift[0] < 0.0, "{shift:?}");
+        assert!(shift[1].abs() < 1e-6, "{shift:?}");
+    }
+
+    #[test]
+    fn flat_surface_holds_its_particles() {
+        // The concentration drops towards the top row, which would be pushed
+        // out of the fluid if the normal part of the shift were kept
+        let displacement = block_shifts(|_, _| {});
+        for i in 3..SIDE - 3 {
+            let shift = displacement[index(i, SIDE - 1)];
+            assert!(shift.iter().all(|s| s.abs() < 1e-6), "{i}: {shift:?}");
+        }
+    }
+}
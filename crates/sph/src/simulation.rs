@@ -1,23 +1,83 @@
 use crate::constants::{GLOBALS, N};
 use crate::state::State;
-use crate::spatial_hash::{populate_grid, find_neighbors};
-use crate::kernel::{kernel, d_kernel};
+use crate::spatial_hash::SpatialIndex;
+use crate::neighbor_search::{NeighborSearch, UniformGrid};
+use crate::verlet::prune_neighbors;
+use crate::kernel::{kernel, d_kernel, d_kernel_nd};
+use crate::smoothing_length::SmoothingLengthSolver;
+use crate::gas_dynamics::{accumulate_gas_forces, FluidModel, GasParameters, GasParticles};
+use crate::equation_of_state::EquationOfState;
+use crate::self_gravity::{add_self_gravity, GravityParameters};
+use crate::multiphase::{accumulate_multiphase_forces, phase_densities, PhaseParticles};
+use crate::material::MaterialKind;
+use crate::rheology::{accumulate_viscous_forces, effective_viscosities, shear_rates, velocity_gradients, FlowParticles};
+use crate::granular::{accumulate_granular_forces, update_stresses, GranularParticles};
+use crate::continuity::{accumulate_density_rates, ContinuityParticles, DensityEvolution};
+use crate::density_filter::{filter_densities, FilterParticles};
+use crate::kernel_correction::{correction_matrices, corrected_gradient, CorrectionParticles};
+use crate::free_surface::{detect_free_surface, FreeSurfaceParticles};
+use crate::shifting::{shifting_displacements, ShiftingParameters, ShiftingParticles};
+use crate::vorticity::{accumulate_confinement_forces, vorticities, VorticityParticles};
+use crate::xsph::{xsph_corrections, XsphParticles};
+use crate::turbulence::{accumulate_sps_forces, sps_stresses, SubParticleScale, TurbulentParticles};
+use crate::elasticity::{accumulate_elastic_forces, deformation_gradients, ElasticParticles};
+use crate::heat::{accumulate_heat_conduction, apply_thermal_walls, boussinesq_acceleration, ThermalParticles};
+
+// Largest factor by which a smoothing length may grow in one step
+const SMOOTHING_LENGTH_GROWTH: f32 = 1.25;
+
+/// Largest smoothing length particle `i` may take this step without
+/// outgrowing its neighbor list.
+fn max_smoothing_length(state: &State, i: usize) -> f32 {
+    let smoothing_radius = GLOBALS.smoothing_radius as f32;
+    if GLOBALS.verlet_lists {
+        smoothing_radius
+    } else {
+        (SMOOTHING_LENGTH_GROWTH * state.h[i]).min(smoothing_radius)
+    }
+}
+
+fn rebuild_neighbors(state: &mut State) {
+    let search_radius = GLOBALS.neighbor_search_radius() as f32;
+
+    let radii: [f32; N] = std::array::from_fn(|i| {
+        if GLOBALS.adaptive_smoothing && !GLOBALS.verlet_lists {
+            max_smoothing_length(state, i)
+        } else {
+            search_radius
+        }
+    });
+    let positions = [&state.x[..], &state.y[..], &state.z[..]];
+
+    match GLOBALS.spatial_index {
+        SpatialIndex::Dense => {
+            let mut uniform_grid = UniformGrid {
+                grid: &state.grid,
+                contents: &mut state.cell_contents,
+                grid_map: &mut state.point_to_cell,
+                inv_cell_length: 1.0 / search_radius,
+            };
+            uniform_grid.find_neighbors(positions, &radii, &mut state.neighbors);
+        }
+        SpatialIndex::Hashed => NeighborSearch::find_neighbors(&mut state.hashed_grid, positions, &radii, &mut state.neighbors),
+        SpatialIndex::KdTree => state.kd_tree.find_neighbors(positions, &radii, &mut state.neighbors),
+    }
+
+    if GLOBALS.verlet_lists {
+        // Keep only pairs within the skinned cutoff so the lists can be reused
+        prune_neighbors(&state.x, &state.y, &state.z, &mut state.neighbors, search_radius);
+        state.verlet.record_positions(&state.x, &state.y, &state.z);
+    }
+
+    state.diagnostics.neighbor_rebuilds += 1;
+}
 
 fn initialize_timestep(state: &mut State) {
-    // Populate the spatial grid for neighbor finding
-    populate_grid(
-        &state.x,
-        &state.y,
-        &state.z,
-        &state.grid,
-        &mut state.cell_contents,
-        &mut state.point_to_cell,
-        N,
-        state.inv_h,
-    );
-    
-    // Find neighbors based on the populated grid
-    find_neighbors(&state.grid, &state.cell_contents, &mut state.neighbors);
+    if !GLOBALS.verlet_lists || state.verlet.needs_rebuild(&state.x, &state.y, &state.z) {
+        rebuild_neighbors(state);
+    }
+    state.diagnostics.steps += 1;
+    let evolve_density = evolves_density();
     
     // Store previous accelerations, reset current ones, and reset densities in single loop
     for i in 0..N {
@@ -28,11 +88,24 @@ fn initialize_timestep(state: &mut State) {
         state.ax[i] = 0.0;
         state.ay[i] = 0.0;
         state.az[i] = 0.0;
-        
-        state.rho[i] = 0.0;
+
+        state.du_[i] = state.du[i];
+        state.du[i] = 0.0;
+        state.dtemperature[i] = 0.0;
+
+        state.drho[i] = 0.0;
+        if !evolve_density {
+            state.rho[i] = 0.0;
+        }
     }
 }
 
+/// Whether densities are carried over from the last step and integrated
+/// from the continuity equation.
+fn evolves_density() -> bool {
+    GLOBALS.density_evolution == DensityEvolution::Continuity
+}
+
 fn add_density(state: &mut State, i: usize, j: usize, symm: bool) {
     let dx = state.x[i] - state.x[j];
     let dy = state.y[i] - state.y[j];
@@ -46,11 +119,16 @@ fn add_density(state: &mut State, i: usize, j: usize, symm: bool) {
     }
 
     let d = r2.sqrt();
-    let density = kernel(d as f64, state.inv_h as f64) as f32 * state.particle_mass;
+    let w = kernel(d as f64, state.inv_h as f64) as f32;
+    let material_i = &state.materials[state.material[i]];
+    let material_j = &state.materials[state.material[j]];
 
-    state.rho[i] += density;
-    if symm {
-        state.rho[j] += density;
+    // Tracers take their density from the fluid without adding to it
+    if material_j.interacts() {
+        state.rho[i] += w * material_j.mass;
+    }
+    if symm && material_i.interacts() {
+        state.rho[j] += w * material_i.mass;
     }
 }
 
@@ -67,16 +145,119 @@ fn add_densities(state: &mut State) {
     }
 }
 
+/// Mass each particle lends to its neighbors; tracers lend none.
+fn particle_masses(state: &State) -> [f32; N] {
+    std::array::from_fn(|i| {
+        let material = &state.materials[state.material[i]];
+        if material.interacts() { material.mass } else { 0.0 }
+    })
+}
+
+fn compute_adaptive_densities(state: &mut State) {
+    let masses = particle_masses(state);
+    let solver = SmoothingLengthSolver {
+        target_neighbors: GLOBALS.target_neighbors as f32,
+        mass: &masses,
+        dim: GLOBALS.dim,
+        tolerance: 1e-3,
+        max_iterations: 10,
+    };
+    let max_h: [f32; N] = std::array::from_fn(|i| max_smoothing_length(state, i));
+    let positions = [&state.x[..], &state.y[..], &state.z[..]];
+
+    solver.solve(positions, &state.neighbors, &max_h, &mut state.h, &mut state.rho, &mut state.omega);
+}
+
+fn compute_phase_densities(state: &mut State) {
+    phase_densities(
+        [&state.x, &state.y, &state.z],
+        &state.neighbors,
+        &state.material,
+        state.materials.as_slice(),
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        &mut state.rho,
+    );
+}
+
+fn add_density_rates(state: &mut State) {
+    let particles = ContinuityParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        material: &state.material,
+    };
+
+    accumulate_density_rates(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        GLOBALS.density_diffusion as f32,
+        &mut state.drho,
+    );
+}
+
+/// Replaces the densities by their filtered values, removing the noise that
+/// accumulates when they are evolved with the continuity equation.
+fn reinitialize_densities(state: &mut State) {
+    let particles = FilterParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        material: &state.material,
+    };
+    let mut filtered = [0.0; N];
+
+    filter_densities(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        GLOBALS.density_filter,
+        &mut filtered,
+    );
+    state.rho = filtered;
+}
+
+/// Pressure of each particle from the equation of state of its own material.
 fn compute_pressures(state: &mut State) {
     for i in 0..N {
-        let density = state.rho[i];
-        let pressure = state.tait_b * ((density * state.inv_reference_density).powf(GLOBALS.tait_gamma as f32) - 1.0);
-        state.p[i] = pressure;
+        let equation_of_state = &state.materials[state.material[i]].equation_of_state;
+        state.p[i] = equation_of_state.pressure(state.rho[i], state.u[i]);
     }
 }
 
-fn accelerate_along_pressure_gradient(state: &mut State, i: usize, j: usize) {
-    if state.rho[i] <= 0.0 || state.rho[j] <= 0.0 {
+/// Whether the pair exerts pressure forces on each other: tracers feel no
+/// forces, two particles that cannot move need none, and particles of the
+/// same solid or granular body act on each other through their stress.
+fn interacting_pair(state: &State, i: usize, j: usize) -> bool {
+    let material_i = &state.materials[state.material[i]];
+    let material_j = &state.materials[state.material[j]];
+    let same_body = material_i.carries_stress() && state.material[i] == state.material[j];
+    material_i.interacts() && material_j.interacts() && (material_i.is_mobile() || material_j.is_mobile()) && !same_body
+}
+
+fn compute_kernel_corrections(state: &mut State) {
+    let particles = CorrectionParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        material: &state.material,
+    };
+
+    correction_matrices(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        &mut state.kernel_correction,
+    );
+}
+
+fn accelerate_along_pressure_gradient(state: &mut State, i: usize, j: usize, corrected: bool) {
+    if state.rho[i] <= 0.0 || state.rho[j] <= 0.0 || !interacting_pair(state, i, j) {
         return;
     }
 
@@ -107,89 +288,849 @@ fn accelerate_along_pressure_gradient(state: &mut State, i: usize, j: usize) {
     let dx_normed = dx * inv_d;
     let dy_normed = dy * inv_d;
 
-    let scale = d_kernel(d as f64, state.inv_h as f64) as f32 * (pi + pj) * state.particle_mass;
+    let scale = d_kernel(d as f64, state.inv_h as f64) as f32 * (pi + pj);
+
+    let mut ax = dx_normed * scale;
+    let mut ay = dy_normed * scale;
+
+    // Averaging the corrections of both particles keeps the force antisymmetric
+    if corrected {
+        let corrected_i = corrected_gradient(&state.kernel_correction[i], [ax, ay, 0.0]);
+        let corrected_j = corrected_gradient(&state.kernel_correction[j], [ax, ay, 0.0]);
+        ax = 0.5 * (corrected_i[0] + corrected_j[0]);
+        ay = 0.5 * (corrected_i[1] + corrected_j[1]);
+    }
+
+    let mass_i = state.materials[state.material[i]].mass;
+    let mass_j = state.materials[state.material[j]].mass;
+
+    state.ax[i] -= ax * mass_j;
+    state.ay[i] -= ay * mass_j;
+
+    state.ax[j] += ax * mass_i;
+    state.ay[j] += ay * mass_i;
+}
+
+fn add_momentum(state: &mut State) {
+    if GLOBALS.kernel_gradient_correction {
+        compute_kernel_corrections(state);
+    }
+    for i in 0..N {
+        let neighbor_count = state.neighbors[i].len();
+        for j_idx in 0..neighbor_count {
+            let j = state.neighbors[i][j_idx];
+            accelerate_along_pressure_gradient(state, i, j, GLOBALS.kernel_gradient_correction);
+        }
+    }
+}
+
+fn accelerate_along_pressure_gradient_grad_h(state: &mut State, i: usize, j: usize) {
+    if state.rho[i] <= 0.0 || state.rho[j] <= 0.0 || !interacting_pair(state, i, j) {
+        return;
+    }
+
+    let dx = state.x[i] - state.x[j];
+    let dy = state.y[i] - state.y[j];
+
+    let r2 = dx * dx + dy * dy;
+
+    let h_i = state.h[i];
+    let h_j = state.h[j];
+    let support = h_i.max(h_j);
+    if r2 > support * support {
+        return;
+    }
+
+    let d = r2.sqrt();
+
+    if d < 0.1 * (h_i + h_j) {
+        return;
+    }
+
+    // Each particle's term uses its own smoothing length and grad-h factor
+    let pi = state.p[i] / (state.omega[i] * state.rho[i] * state.rho[i]);
+    let pj = state.p[j] / (state.omega[j] * state.rho[j] * state.rho[j]);
+
+    let d_kernel_i = d_kernel_nd(d as f64, 1.0 / h_i as f64, GLOBALS.dim) as f32;
+    let d_kernel_j = d_kernel_nd(d as f64, 1.0 / h_j as f64, GLOBALS.dim) as f32;
+
+    let inv_d = 1.0 / d;
+
+    let dx_normed = dx * inv_d;
+    let dy_normed = dy * inv_d;
+
+    let scale = d_kernel_i * pi + d_kernel_j * pj;
 
     let ax = dx_normed * scale;
     let ay = dy_normed * scale;
 
-    state.ax[i] -= ax;
-    state.ay[i] -= ay;
+    let mass_i = state.materials[state.material[i]].mass;
+    let mass_j = state.materials[state.material[j]].mass;
 
-    state.ax[j] += ax;
-    state.ay[j] += ay;
+    state.ax[i] -= ax * mass_j;
+    state.ay[i] -= ay * mass_j;
+
+    state.ax[j] += ax * mass_i;
+    state.ay[j] += ay * mass_i;
 }
 
-fn add_momentum(state: &mut State) {
+fn add_momentum_grad_h(state: &mut State) {
     for i in 0..N {
         let neighbor_count = state.neighbors[i].len();
         for j_idx in 0..neighbor_count {
             let j = state.neighbors[i][j_idx];
-            accelerate_along_pressure_gradient(state, i, j);
+            accelerate_along_pressure_gradient_grad_h(state, i, j);
         }
     }
 }
 
+fn add_multiphase_forces(state: &mut State) {
+    let particles = PhaseParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        p: &state.p,
+        material: &state.material,
+    };
+
+    accumulate_multiphase_forces(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+    );
+}
+
+/// Viscosity of every particle at its current shear rate. The velocity
+/// gradient is only needed when some material is non-Newtonian.
+fn compute_viscosities(state: &mut State) {
+    let materials = state.materials.as_slice();
+    if materials.iter().any(|material| !material.rheology.is_newtonian()) {
+        let particles = FlowParticles {
+            position: [&state.x, &state.y, &state.z],
+            velocity: [&state.vx, &state.vy, &state.vz],
+            rho: &state.rho,
+            material: &state.material,
+        };
+        shear_rates(
+            &particles,
+            materials,
+            &state.neighbors,
+            GLOBALS.smoothing_radius as f32,
+            GLOBALS.dim,
+            &mut state.shear_rate,
+        );
+    }
+
+    effective_viscosities(materials, &state.material, &state.shear_rate, &mut state.viscosity);
+}
+
+fn add_viscous_forces(state: &mut State) {
+    let particles = FlowParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        material: &state.material,
+    };
+
+    accumulate_viscous_forces(
+        &particles,
+        state.materials.as_slice(),
+        &state.viscosity,
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+    );
+}
+
+/// Adds the sub-particle-scale stress of the unresolved turbulence.
+fn add_turbulent_stresses(state: &mut State) {
+    let flow = FlowParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        material: &state.material,
+    };
+    let mut velocity_gradient = vec![[[0.0; 3]; 3]; N];
+    velocity_gradients(
+        &flow,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        &mut velocity_gradient,
+    );
+    let closure = SubParticleScale {
+        smagorinsky: GLOBALS.smagorinsky_constant as f32,
+        blin: GLOBALS.blin_constant as f32,
+    };
+    let mut tau = vec![[[0.0; 3]; 3]; N];
+    sps_stresses(
+        state.materials.as_slice(),
+        &state.material,
+        &state.rho,
+        &velocity_gradient,
+        closure,
+        GLOBALS.dim,
+        &mut tau,
+    );
+
+    let particles = TurbulentParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        tau: &tau,
+        material: &state.material,
+    };
+
+    accumulate_sps_forces(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+    );
+}
+
+fn add_elastic_forces(state: &mut State) {
+    deformation_gradients(&state.reference, [&state.x, &state.y, &state.z], GLOBALS.dim, &mut state.deformation_gradient);
+
+    let particles = ElasticParticles {
+        position: [&state.x, &state.y, &state.z],
+        deformation_gradient: &state.deformation_gradient,
+        material: &state.material,
+    };
+
+    accumulate_elastic_forces(
+        &state.reference,
+        &particles,
+        state.materials.as_slice(),
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+    );
+}
+
+/// Advances the stress of granular particles with the current velocity
+/// gradient and adds the forces it exerts.
+fn add_granular_forces(state: &mut State) {
+    let flow = FlowParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        material: &state.material,
+    };
+    let mut velocity_gradient = vec![[[0.0; 3]; 3]; N];
+    velocity_gradients(
+        &flow,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        &mut velocity_gradient,
+    );
+    update_stresses(
+        state.materials.as_slice(),
+        &state.material,
+        &state.p,
+        &velocity_gradient,
+        GLOBALS.timestep as f32,
+        &mut state.stress,
+    );
+
+    let particles = GranularParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        stress: &state.stress,
+        material: &state.material,
+    };
+
+    accumulate_granular_forces(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+    );
+}
+
+fn conduct_heat(state: &mut State) {
+    let particles = ThermalParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        temperature: &state.temperature,
+        material: &state.material,
+    };
+
+    accumulate_heat_conduction(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        &mut state.dtemperature,
+    );
+}
+
+fn add_buoyancy(state: &mut State) {
+    let gravity = [0.0, GLOBALS.gravity as f32, 0.0];
+    let reference_temperature = GLOBALS.reference_temperature as f32;
+
+    for i in 0..N {
+        let thermal_expansion = state.materials[state.material[i]].thermal_expansion;
+        let a = boussinesq_acceleration(gravity, thermal_expansion, state.temperature[i] - reference_temperature);
+        state.ax[i] += a[0];
+        state.ay[i] += a[1];
+    }
+}
+
+fn update_temperatures(state: &mut State) {
+    let dt = GLOBALS.timestep as f32;
+    for i in 0..N {
+        state.temperature[i] += state.dtemperature[i] * dt;
+    }
+
+    apply_thermal_walls(&state.thermal_walls, [&state.x, &state.y, &state.z], &mut state.temperature);
+}
+
+fn add_gravity_between_particles(state: &mut State) {
+    let params = GravityParameters {
+        gravitational_constant: GLOBALS.gravitational_constant as f32,
+        opening_angle: GLOBALS.opening_angle as f32,
+        softening: GLOBALS.gravity_softening as f32,
+    };
+    let masses = particle_masses(state);
+
+    add_self_gravity(
+        &mut state.gravity_tree,
+        [&state.x, &state.y, &state.z],
+        &masses,
+        &params,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+    );
+}
+
+fn add_gas_forces(state: &mut State) {
+    let params = GasParameters {
+        gamma: GLOBALS.gas_gamma as f32,
+        alpha: GLOBALS.viscosity_alpha as f32,
+        beta: GLOBALS.viscosity_beta as f32,
+        smoothing_radius: GLOBALS.smoothing_radius as f32,
+        dim: GLOBALS.dim,
+    };
+    let masses = particle_masses(state);
+    let particles = GasParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        p: &state.p,
+        mass: &masses,
+    };
+
+    accumulate_gas_forces(
+        &particles,
+        &state.neighbors,
+        &params,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+        &mut state.du,
+    );
+}
+
 fn leapfrog(state: &mut State) {
     let dt = GLOBALS.timestep as f32;
     let dt_sq_half = 0.5 * dt * dt;
     let dt_half = 0.5 * dt;
     
+    // Stiff materials complete the previous step's kick before drifting with
+    // the current acceleration (velocity Verlet); drifting with the previous
+    // one makes their oscillations grow
     for i in 0..N {
-        state.x[i] += state.vx[i] * dt + state.ax_[i] * dt_sq_half;
-        state.y[i] += state.vy[i] * dt + state.ay_[i] * dt_sq_half;
-        
-        state.vx[i] += (state.ax_[i] + state.ax[i]) * dt_half;
-        state.vy[i] += (state.ay_[i] + state.ay[i]) * dt_half;
+        if matches!(state.materials[state.material[i]].kind, MaterialKind::Solid | MaterialKind::Granular) {
+            state.vx[i] += (state.ax_[i] + state.ax[i]) * dt_half;
+            state.vy[i] += (state.ay_[i] + state.ay[i]) * dt_half;
+            state.u[i] += (state.du_[i] + state.du[i]) * dt_half;
+        }
+    }
+
+    // With XSPH, fluid particles drift with a smoothed velocity
+    let mut correction = vec![[0.0; 3]; N];
+    if GLOBALS.xsph {
+        let particles = XsphParticles {
+            position: [&state.x, &state.y, &state.z],
+            velocity: [&state.vx, &state.vy, &state.vz],
+            rho: &state.rho,
+            material: &state.material,
+        };
+        xsph_corrections(
+            &particles,
+            state.materials.as_slice(),
+            &state.neighbors,
+            GLOBALS.smoothing_radius as f32,
+            GLOBALS.dim,
+            GLOBALS.xsph_epsilon as f32,
+            &mut correction,
+        );
+    }
+
+    for (i, [dvx, dvy, _]) in correction.into_iter().enumerate() {
+        match state.materials[state.material[i]].kind {
+            MaterialKind::Fluid => {
+                state.x[i] += (state.vx[i] + dvx) * dt + state.ax_[i] * dt_sq_half;
+                state.y[i] += (state.vy[i] + dvy) * dt + state.ay_[i] * dt_sq_half;
+
+                state.vx[i] += (state.ax_[i] + state.ax[i]) * dt_half;
+                state.vy[i] += (state.ay_[i] + state.ay[i]) * dt_half;
+
+                state.u[i] += (state.du_[i] + state.du[i]) * dt_half;
+            }
+            MaterialKind::Solid | MaterialKind::Granular => {
+                state.x[i] += (state.vx[i] + dvx) * dt + state.ax[i] * dt_sq_half;
+                state.y[i] += (state.vy[i] + dvy) * dt + state.ay[i] * dt_sq_half;
+            }
+            MaterialKind::Tracer => {
+                state.x[i] += state.vx[i] * dt;
+                state.y[i] += state.vy[i] * dt;
+            }
+            MaterialKind::Boundary => {}
+        }
+        state.rho[i] += state.drho[i] * dt;
+    }
+}
+
+/// Flags the particles on the free surface. The box walls are not made of
+/// particles, so a particle next to a wall also misses neighbors; it is only
+/// kept on the surface if its normal does not point into that wall.
+fn find_free_surface(state: &mut State) {
+    let particles = FreeSurfaceParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        material: &state.material,
+    };
+
+    detect_free_surface(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        &mut state.free_surface,
+        &mut state.surface_normal,
+    );
+
+    let smoothing_radius = GLOBALS.smoothing_radius as f32;
+    let box_min = GLOBALS.box_min as f32;
+    let box_max = GLOBALS.box_max as f32;
+    for i in 0..N {
+        if !state.free_surface[i] {
+            continue;
+        }
+        let position = [state.x[i], state.y[i], state.z[i]];
+        let normal = state.surface_normal[i];
+        let against_wall = (0..GLOBALS.dim).any(|a| {
+            (position[a] - box_min < smoothing_radius && normal[a] < -0.5)
+                || (box_max - position[a] < smoothing_radius && normal[a] > 0.5)
+        });
+        if against_wall {
+            state.free_surface[i] = false;
+            state.surface_normal[i] = [0.0; 3];
+        }
     }
 }
 
+fn compute_vorticities(state: &mut State) {
+    let particles = VorticityParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        material: &state.material,
+    };
+
+    vorticities(
+        &particles,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        &mut state.vorticity,
+    );
+}
+
+fn add_vorticity_confinement(state: &mut State) {
+    let particles = VorticityParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        material: &state.material,
+    };
+
+    accumulate_confinement_forces(
+        &particles,
+        &state.vorticity,
+        state.materials.as_slice(),
+        &state.neighbors,
+        GLOBALS.smoothing_radius as f32,
+        GLOBALS.dim,
+        GLOBALS.confinement_strength as f32,
+        [&mut state.ax, &mut state.ay, &mut state.az],
+    );
+}
+
+/// Nudges fluid particles towards a uniform distribution. The free surface
+/// found before the drift tells which particles may only move along it.
+fn shift_particles(state: &mut State) {
+    let params = ShiftingParameters {
+        coefficient: GLOBALS.shifting_coefficient as f32,
+        timestep: GLOBALS.timestep as f32,
+        smoothing_radius: GLOBALS.smoothing_radius as f32,
+        dim: GLOBALS.dim,
+    };
+    let particles = ShiftingParticles {
+        position: [&state.x, &state.y, &state.z],
+        velocity: [&state.vx, &state.vy, &state.vz],
+        rho: &state.rho,
+        material: &state.material,
+        free_surface: &state.free_surface,
+        surface_normal: &state.surface_normal,
+    };
+    let mut displacement = vec![[0.0; 3]; N];
+
+    shifting_displacements(&particles, state.materials.as_slice(), &state.neighbors, &params, &mut displacement);
+    for (i, [dx, dy, dz]) in displacement.into_iter().enumerate() {
+        state.x[i] += dx;
+        state.y[i] += dy;
+        state.z[i] += dz;
+    }
+}
+
+/// Tracers take the kernel-weighted mean velocity of the fluid around them.
+fn interpolate_tracer_velocities(state: &mut State) {
+    let has_tracers = state.materials.as_slice().iter().any(|material| material.kind == MaterialKind::Tracer);
+    if !has_tracers {
+        return;
+    }
+
+    let mut weight = [0.0_f32; N];
+    let mut vx = [0.0_f32; N];
+    let mut vy = [0.0_f32; N];
+
+    for i in 0..N {
+        for &j in &state.neighbors[i] {
+            let kind_i = state.materials[state.material[i]].kind;
+            let kind_j = state.materials[state.material[j]].kind;
+
+            let dx = state.x[i] - state.x[j];
+            let dy = state.y[i] - state.y[j];
+            let dz = state.z[i] - state.z[j];
+            let d = (dx * dx + dy * dy + dz * dz).sqrt();
+            let w = kernel(d as f64, state.inv_h as f64) as f32;
+
+            if kind_i == MaterialKind::Tracer && kind_j == MaterialKind::Fluid {
+                weight[i] += w;
+                vx[i] += w * state.vx[j];
+                vy[i] += w * state.vy[j];
+            } else if kind_j == MaterialKind::Tracer && kind_i == MaterialKind::Fluid {
+                weight[j] += w;
+                vx[j] += w * state.vx[i];
+                vy[j] += w * state.vy[i];
+            }
+        }
+    }
+
+    for i in 0..N {
+        if weight[i] > 0.0 {
+            state.vx[i] = vx[i] / weight[i];
+            state.vy[i] = vy[i] / weight[i];
+        }
+    }
+}
+
+/// Reflects particles leaving the box. Granular particles touching a wall
+/// stop instead, giving the container the friction a pile rests on.
 fn reflect(state: &mut State) {
     let box_min = GLOBALS.box_min as f32;
     let box_max = GLOBALS.box_max as f32;
     let is_3d = GLOBALS.dim > 2;
     
     for i in 0..N {
+        let mut contact = [false; 3];
         if state.x[i] < box_min {
             state.x[i] = box_min;
             state.vx[i] *= -1.0;
+            contact[0] = true;
         } else if state.x[i] > box_max {
             state.x[i] = box_max;
             state.vx[i] *= -1.0;
+            contact[0] = true;
         }
         
         if state.y[i] < box_min {
             state.y[i] = box_min;
             state.vy[i] *= -1.0;
+            contact[1] = true;
         } else if state.y[i] > box_max {
             state.y[i] = box_max;
             state.vy[i] *= -1.0;
+            contact[1] = true;
         }
         
         if is_3d {
             if state.z[i] < box_min {
                 state.z[i] = box_min;
                 state.vz[i] *= -1.0;
+                contact[2] = true;
             } else if state.z[i] > box_max {
                 state.z[i] = box_max;
                 state.vz[i] *= -1.0;
+                contact[2] = true;
             }
         }
+
+        // Sand does not bounce; it rubs along the wall instead
+        let material = &state.materials[state.material[i]];
+        if material.is_granular() && contact.contains(&true) {
+            let velocity = [state.vx[i], state.vy[i], state.vz[i]];
+            [state.vx[i], state.vy[i], state.vz[i]] = material.plasticity.wall_contact(velocity, contact);
+        }
     }
 }
 
 pub fn update(state: &mut State) {
     initialize_timestep(state);
-    add_densities(state);
+    if evolves_density() {
+        add_density_rates(state);
+    } else if GLOBALS.multiphase {
+        compute_phase_densities(state);
+    } else if GLOBALS.adaptive_smoothing {
+        compute_adaptive_densities(state);
+    } else {
+        add_densities(state);
+    }
     compute_pressures(state);
+    // Shifting keeps surface particles on the surface it finds
+    if GLOBALS.free_surface_detection || GLOBALS.particle_shifting {
+        find_free_surface(state);
+    }
+    if GLOBALS.vorticity_confinement || GLOBALS.vorticity_output {
+        compute_vorticities(state);
+    }
 
     // Add gravity to accelerations
     let gravity = GLOBALS.gravity as f32;
     for i in 0..N {
         state.ay[i] += gravity;
     }
+    if GLOBALS.self_gravity {
+        add_gravity_between_particles(state);
+    }
+    if GLOBALS.heat_transfer {
+        conduct_heat(state);
+        add_buoyancy(state);
+    }
     
-    add_momentum(state);
+    match GLOBALS.fluid_model {
+        FluidModel::IdealGas => add_gas_forces(state),
+        FluidModel::WeaklyCompressible if GLOBALS.multiphase => add_multiphase_forces(state),
+        FluidModel::WeaklyCompressible if GLOBALS.adaptive_smoothing => add_momentum_grad_h(state),
+        FluidModel::WeaklyCompressible => add_momentum(state),
+    }
+    if GLOBALS.fluid_model == FluidModel::WeaklyCompressible {
+        compute_viscosities(state);
+        add_viscous_forces(state);
+        if GLOBALS.turbulence {
+            add_turbulent_stresses(state);
+        }
+        if GLOBALS.vorticity_confinement {
+            add_vorticity_confinement(state);
+        }
+    }
+    if state.materials.as_slice().iter().any(|material| material.is_solid()) {
+        add_elastic_forces(state);
+    }
+    if state.materials.as_slice().iter().any(|material| material.is_granular()) {
+        add_granular_forces(state);
+    }
     
     reflect(state);
+    interpolate_tracer_velocities(state);
     leapfrog(state);
-}
\ No newline at end of file
+    if GLOBALS.particle_shifting {
+        shift_particles(state);
+    }
+    if GLOBALS.heat_transfer {
+        update_temperatures(state);
+    }
+    if GLOBALS.density_reinitialization && state.diagnostics.steps.is_multiple_of(GLOBALS.density_filter_interval) {
+        reinitialize_densities(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::equation_of_state::{EquationOfStateModel, IdealGas};
+    use crate::material::{Material, MaterialRegistry};
+    use crate::test_support::{half_neighbor_lists, water, SPACING};
+
+    /// Pressure accelerations of a block of water, 40 particles wide and 25
+    /// high, under the linear pressure `p = 2 (H - y)` of a column at rest
+    /// whose top `H` lies half a spacing above the top row, and whether the
+    /// supports of each particle and its neighbors lie inside the block.
+    fn hydrostatic_accelerations(corrected: bool) -> (Vec<[f32; 2]>, Vec<bool>) {
+        let rows = N / 40;
+        let top = (rows as f32 - 0.5) * SPACING;
+        let mut state = State::new();
+        state.materials = MaterialRegistry::default();
+        state.materials.register(water());
+        state.material = [0; N];
+        for i in 0..N {
+            state.x[i] = (i / rows) as f32 * SPACING;
+            state.y[i] = (i % rows) as f32 * SPACING;
+            state.rho[i] = 1.0;
+            state.p[i] = 2.0 * (top - state.y[i]);
+        }
+        state.neighbors = half_neighbor_lists(&[state.x.to_vec(), state.y.to_vec(), state.z.to_vec()]);
+        compute_kernel_corrections(&mut state);
+
+        state.ax = [0.0; N];
+        state.ay = [0.0; N];
+        for i in 0..N {
+            for j in state.neighbors[i].clone() {
+                accelerate_along_pressure_gradient(&mut state, i, j, corrected);
+            }
+        }
+
+        let margin = 2.0 * GLOBALS.smoothing_radius as f32 + 0.01;
+        let interior = (0..N)
+            .map(|i| state.x[i] > margin && state.x[i] < 3.9 - margin && state.y[i] > margin && state.y[i] < top - margin)
+            .collect();
+        ((0..N).map(|i| [state.ax[i], state.ay[i]]).collect(), interior)
+    }
+
+    #[test]
+    fn corrected_pressure_force_balances_a_hydrostatic_column() {
+        let (accel, interior) = hydrostatic_accelerations(true);
+        for (i, a) in accel.iter().enumerate().filter(|&(i, _)| interior[i]) {
+            assert!(a[0].abs() < 1e-3 && (a[1] - 2.0).abs() < 1e-3, "particle {i}: {a:?}");
+        }
+        // The force stays antisymmetric, so momentum is conserved
+        let total = accel.iter().fold([0.0; 2], |sum, a| [sum[0] + a[0], sum[1] + a[1]]);
+        assert!(total.iter().all(|c| c.abs() < 1e-2), "{total:?}");
+
+        // Without the correction the lattice's discrete moment is off
+        let (accel, interior) = hydrostatic_accelerations(false);
+        let error = (0..N).filter(|&i| interior[i]).map(|i| (accel[i][1] - 2.0).abs()).fold(0.0, f32::max);
+        assert!(error > 5e-3, "{error}");
+    }
+
+    /// Adaptive densities and smoothing lengths of a block of water, 40
+    /// particles wide and 25 high, whose first particle is replaced by a
+    /// tracer at `tracer`.
+    fn adaptive_densities_with_tracer(tracer: [f32; 2]) -> State {
+        let rows = N / 40;
+        let mut state = State::new();
+        state.materials = MaterialRegistry::default();
+        let liquid = state.materials.register(water());
+        let marker = state.materials.register(Material { kind: MaterialKind::Tracer, ..water() });
+        state.material = [liquid; N];
+        state.material[0] = marker;
+        for i in 0..N {
+            state.x[i] = (i / rows) as f32 * SPACING;
+            state.y[i] = (i % rows) as f32 * SPACING;
+        }
+        [state.x[0], state.y[0]] = tracer;
+        state.h = [2.0 * SPACING; N];
+        state.neighbors = half_neighbor_lists(&[state.x.to_vec(), state.y.to_vec(), state.z.to_vec()]);
+
+        compute_adaptive_densities(&mut state);
+        state
+    }
+
+    #[test]
+    fn tracers_leave_the_adaptive_density_unchanged() {
+        let inside = adaptive_densities_with_tracer([1.05, 1.05]);
+        let outside = adaptive_densities_with_tracer([100.0, 100.0]);
+        for i in 1..N {
+            assert_eq!(inside.rho[i], outside.rho[i], "particle {i}");
+            assert_eq!(inside.h[i], outside.h[i], "particle {i}");
+        }
+        // The tracer still samples the density around it
+        assert!(inside.rho[0] > 0.5, "{}", inside.rho[0]);
+    }
+
+    #[test]
+    fn pressures_follow_each_particles_equation_of_state() {
+        let mut state = State::new();
+        state.materials = MaterialRegistry::default();
+        let liquid = state.materials.register(water());
+        let air = state.materials.register(Material {
+            name: "air",
+            equation_of_state: EquationOfStateModel::IdealGas(IdealGas { gamma: 1.4 }),
+            ..water()
+        });
+        state.material = std::array::from_fn(|i| if i % 2 == 0 { liquid } else { air });
+        state.rho = [1.2; N];
+        state.u = [5.0; N];
+
+        compute_pressures(&mut state);
+        // Linear water at sound speed 10, and (gamma - 1) rho u for air
+        for i in 0..N {
+            let expected = if i % 2 == 0 { 100.0 * 0.2 } else { 0.4 * 1.2 * 5.0 };
+            assert!((state.p[i] - expected).abs() < 1e-4, "particle {i}: {}", state.p[i]);
+        }
+    }
+
+    /// Largest displacement of a solid particle on a spring of angular
+    /// frequency `omega` during each of `periods` periods, starting at rest
+    /// one unit from its anchor.
+    fn spring_amplitudes(omega: f32, periods: usize) -> Vec<f32> {
+        let mut state = State::new();
+        let solid = state.materials.register(Material { kind: MaterialKind::Solid, ..water() });
+        state.material = [solid; N];
+        state.vx = [0.0; N];
+        state.vy = [0.0; N];
+        let anchor = state.x;
+        state.x = anchor.map(|x| x + 1.0);
+        state.ax = [-omega * omega; N];
+
+        let dt = GLOBALS.timestep as f32;
+        let steps_per_period = (2.0 * std::f32::consts::PI / (omega * dt)).round() as usize;
+        let mut amplitudes = vec![0.0_f32; periods];
+        for step in 0..periods * steps_per_period {
+            state.ax_ = state.ax;
+            state.ax = std::array::from_fn(|i| -omega * omega * (state.x[i] - anchor[i]));
+            leapfrog(&mut state);
+            let amplitude = &mut amplitudes[step / steps_per_period];
+            *amplitude = amplitude.max((state.x[0] - anchor[0]).abs());
+        }
+        amplitudes
+    }
+
+    #[test]
+    fn leapfrog_keeps_the_energy_of_a_harmonic_oscillator() {
+        // About 60 steps per period, for 50 periods
+        let amplitudes = spring_amplitudes(200.0, 50);
+        for (period, amplitude) in amplitudes.iter().enumerate() {
+            assert!((amplitude - 1.0).abs() < 1e-2, "period {period}: {amplitude}");
+        }
+    }
+
+    #[test]
+    fn fluid_particles_drift_with_the_previous_acceleration() {
+        let mut state = State::new();
+        let fluid = state.materials.as_slice().iter().position(|material| material.kind == MaterialKind::Fluid).unwrap();
+        state.material = [fluid; N];
+        state.x = [0.0; N];
+        state.vx = [1.0; N];
+        state.ax_ = [2.0; N];
+        state.ax = [4.0; N];
+
+        leapfrog(&mut state);
+        let dt = GLOBALS.timestep as f32;
+        assert!((state.x[0] - (dt + dt * dt)).abs() < 1e-9, "{}", state.x[0]);
+        assert!((state.vx[0] - (1.0 + 3.0 * dt)).abs() < 1e-6, "{}", state.vx[0]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::f32::consts::PI;
+
+use crate::kernel::{dh_kernel_nd, kernel_nd};
+
+/// Smallest grad-h correction; the pressure force divides by it.
+pub const MIN_OMEGA: f32 = 0.1;
+
+/// Volume of the unit ball in `dim` dimensions.
+fn unit_ball_volume(dim: usize) -> f32 {
+    match dim {
+        1 => 2.0,
+        2 => PI,
+        _ => 4.0 * PI / 3.0,
+    }
+}
+
+/// Solves for per-particle smoothing lengths such that each kernel support
+/// holds a fixed mass of `target_neighbors` particles (Springel & Hernquist
+/// 2002), i.e. `rho_i(h) * V_d * h^d = target_neighbors * m_i`.
+#[derive(Debug, Clone, Copy)]
+pub struct SmoothingLengthSolver<'a> {
+    pub target_neighbors: f32,
+    /// Mass of each particle
+    pub mass: &'a [f32],
+    pub dim: usize,
+    pub tolerance: f32,
+    pub max_iterations: usize,
+}
+
+impl SmoothingLengthSolver<'_> {
+    /// Density of particle `i` implied by the smoothing length through the
+    /// neighbor constraint.
+    pub fn density_for(&self, i: usize, h: f32) -> f32 {
+        self.target_neighbors * self.mass[i] / (unit_ball_volume(self.dim) * h.powi(self.dim as i32))
+    }
+
+    /// Newton-Raphson iteration on `h`, starting from the values in `h` and
+    /// never exceeding `max_h`, which must not exceed the radius the neighbor
+    /// lists were built with. On return `rho` holds the summation density at
+    /// the converged `h` and `omega` the grad-h correction
+    /// `1 - (dh/drho) sum_j m_j dW_ij/dh`, no less than `MIN_OMEGA`, or 1
+    /// where `h` sits at `max_h` or the density vanishes. Massless particles
+    /// keep their smoothing length and only sample the density.
+    pub fn solve(
+        &self,
+        positions: [&[f32]; 3],
+        neighbors: &[Vec<usize>],
+        max_h: &[f32],
+        h: &mut [f32],
+        rho: &mut [f32],
+        omega: &mut [f32],
+    ) -> usize {
+        let n = h.len();
+        let mut dh_rho = vec![0.0_f32; n];
+        let mut converged = vec![false; n];
+        let mut iterations = 0;
+
+        while iterations < self.max_iterations {
+            iterations += 1;
+            self.sum_density(positions, neighbors, h, rho, &mut dh_rho);
+
+            let mut all_converged = true;
+            for i in 0..n {
+                if converged[i] {
+                    continue;
+                }
+
+                let target = self.density_for(i, h[i]);
+                if target <= 0.0 {
+                    // A massless particle sets no constraint; it keeps its length
+                    converged[i] = true;
+                    continue;
+                }
+                let residual = rho[i] - target;
+                if residual.abs() <= self.tolerance * target {
+                    converged[i] = true;
+                    continue;
+                }
+                all_converged = false;
+
+                let slope = dh_rho[i] + self.dim as f32 * target / h[i];
+                let step = if slope > 0.0 { residual / slope } else { 0.0 };
+                let h_new = if step != 0.0 {
+                    h[i] - step
+                } else {
+                    // Fall back to the fixed-point update when Newton stalls
+                    h[i] * (target / rho[i].max(f32::MIN_POSITIVE)).powf(1.0 / self.dim as f32)
+                };
+                h[i] = h_new.clamp(0.8 * h[i], (1.25 * h[i]).min(max_h[i]));
+            }
+
+            if all_converged {
+                break;
+            }
+        }
+
+        self.sum_density(positions, neighbors, h, rho, &mut dh_rho);
+        for i in 0..n {
+            omega[i] = if h[i] >= max_h[i] || rho[i] <= 0.0 {
+                // Capped lengths do not follow the density, so no correction
+                1.0
+            } else {
+                // Keep the force denominators away from zero in sparse regions
+                (1.0 + h[i] / (self.dim as f32 * rho[i]) * dh_rho[i]).max(MIN_OMEGA)
+            };
+        }
+
+        iterations
+    }
+
+    fn sum_density(
+        &self,
+        positions: [&[f32]; 3],
+        neighbors: &[Vec<usize>],
+        h: &[f32],
+        rho: &mut [f32],
+        dh_rho: &mut [f32],
+    ) {
+        let dim = self.dim;
+        let mass = |i: usize| self.mass[i] as f64;
+
+        for i in 0..h.len() {
+            // Self contribution
+            let inv_h = 1.0 / h[i] as f64;
+            rho[i] = (mass(i) * kernel_nd(0.0, inv_h, dim)) as f32;
+            dh_rho[i] = (mass(i) * dh_kernel_nd(0.0, inv_h, dim)) as f32;
+        }
+
+        for (i, neighbor_list) in neighbors.iter().enumerate() {
+            for &j in neighbor_list {
+                let dx = positions[0][i] - positions[0][j];
+                let dy = positions[1][i] - positions[1][j];
+                let dz = positions[2][i] - positions[2][j];
+                let r = ((dx * dx + dy * dy + dz * dz) as f64).sqrt();
+
+                let inv_h_i = 1.0 / h[i] as f64;
+                let inv_h_j = 1.0 / h[j] as f64;
+
+                rho[i] += (mass(j) * kernel_nd(r, inv_h_i, dim)) as f32;
+                dh_rho[i] += (mass(j) * dh_kernel_nd(r, inv_h_i, dim)) as f32;
+                rho[j] += (mass(i) * kernel_nd(r, inv_h_j, dim)) as f32;
+                dh_rho[j] += (mass(i) * dh_kernel_nd(r, inv_h_j, dim)) as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kd_tree::KdTree;
+    use crate::neighbor_search::NeighborSearch;
+
+    fn lattice(side: usize, spacing: f32) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+        let mut px = Vec::new();
+        let mut py = Vec::new();
+        for i in 0..side {
+            for j in 0..side {
+                // Slight perturbation so the lattice is not perfectly regular
+                let jitter = 0.05 * spacing * (((i * 7 + j * 13) % 5) as f32 - 2.0) / 2.0;
+                px.push(i as f32 * spacing + jitter);
+                py.push(j as f32 * spacing - jitter);
+            }
+        }
+        let n = px.len();
+        (px, py, vec![0.0; n])
+    }
+
+    /// Solves a lattice whose particle masses are `mass(x)` times the unit
+    /// density mass, returning the target densities, `h`, `rho`, `omega` and
+    /// the index of a central particle.
+    fn solve_lattice(target_neighbors: f32, mass: impl Fn(f32) -> f32) -> (Vec<f32>, Vec<f32>, Vec<f32>, Vec<f32>, usize) {
+        let side = 24;
+        let spacing = 0.1;
+        let (px, py, pz) = lattice(side, spacing);
+        let masses: Vec<f32> = px.iter().map(|&x| mass(x) * spacing * spacing).collect();
+        let positions = [&px[..], &py[..], &pz[..]];
+        let n = px.len();
+        let max_h = vec![1.0; n];
+
+        let mut neighbors = vec![Vec::new(); n];
+        KdTree::default().find_neighbors(positions, &max_h, &mut neighbors);
+
+        let solver = SmoothingLengthSolver {
+            target_neighbors,
+            mass: &masses,
+            dim: 2,
+            tolerance: 1e-4,
+            max_iterations: 30,
+        };
+        let mut h = vec![0.3; n];
+        let mut rho = vec![0.0; n];
+        let mut omega = vec![0.0; n];
+        solver.solve(positions, &neighbors, &max_h, &mut h, &mut rho, &mut omega);
+
+        let target = (0..n).map(|i| solver.density_for(i, h[i])).collect();
+        // Index of a particle in the middle of the lattice
+        let center = (side / 2) * side + side / 2;
+        (target, h, rho, omega, center)
+    }
+
+    #[test]
+    fn solved_lengths_satisfy_neighbor_constraint() {
+        let (target, h, rho, _, _) = solve_lattice(30.0, |_| 1.0);
+
+        for i in 0..h.len() {
+            assert!(
+                (rho[i] - target[i]).abs() <= 2e-4 * target[i],
+                "particle {} did not converge: rho={}, target={}", i, rho[i], target[i]
+            );
+        }
+    }
+
+    #[test]
+    fn interior_density_and_length_match_lattice() {
+        let target_neighbors = 30.0;
+        let (_, h, rho, omega, center) = solve_lattice(target_neighbors, |_| 1.0);
+
+        // Unit density lattice: pi h^2 = target_neighbors * spacing^2
+        let expected_h = (target_neighbors * 0.01 / PI).sqrt();
+        assert!((rho[center] - 1.0).abs() < 0.02, "interior density {}", rho[center]);
+        assert!((h[center] - expected_h).abs() < 0.02 * expected_h, "interior h {}", h[center]);
+        assert!((omega[center] - 1.0).abs() < 0.1, "interior omega {}", omega[center]);
+    }
+
+    #[test]
+    fn edge_particles_grow_their_support() {
+        let (_, h, _, _, center) = solve_lattice(30.0, |_| 1.0);
+
+        // The corner particle sees a quarter of the neighbors and must reach
+        // further out to enclose the same mass
+        assert!(h[0] > 1.5 * h[center]);
+    }
+
+    #[test]
+    fn heavier_particles_sum_to_a_higher_density() {
+        // Left half twice as heavy as the right half, on the same lattice
+        let (_, h, rho, _, _) = solve_lattice(30.0, |x| if x < 1.15 { 2.0 } else { 1.0 });
+        let (left, right) = (5 * 24 + 12, 18 * 24 + 12);
+
+        assert!((rho[left] - 2.0).abs() < 0.04, "left density {}", rho[left]);
+        assert!((rho[right] - 1.0).abs() < 0.02, "right density {}", rho[right]);
+        assert!((h[left] - h[right]).abs() < 0.02 * h[right], "{} {}", h[left], h[right]);
+    }
+
+    #[test]
+    fn omega_stays_positive_for_an_isolated_particle() {
+        // A particle alone in its support only sees itself, where
+        // `h dW(0)/dh = -dim W(0)` cancels the raw correction exactly
+        let positions = [&[0.0][..], &[0.0][..], &[0.0][..]];
+        let solver = SmoothingLengthSolver {
+            target_neighbors: 30.0,
+            mass: &[0.01],
+            dim: 2,
+            tolerance: 1e-4,
+            max_iterations: 3,
+        };
+        let (mut h, mut rho, mut omega) = (vec![0.3], vec![0.0], vec![0.0]);
+        solver.solve(positions, &[Vec::new()], &[100.0], &mut h, &mut rho, &mut omega);
+
+        assert!(h[0] < 100.0);
+        assert_eq!(omega[0], MIN_OMEGA);
+    }
+}
@@ -33,6 +33,7 @@ pub fn hash(x: i32, y: i32, z: i32, grid: &Grid) -> usize {
     (x as usize) + count[0] * ((y as usize) + count[1] * (z as usize))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn populate_grid(
     px: &[f32],
     py: &[f32], 
@@ -104,9 +105,310 @@ pub fn find_neighbors(
     }
 }
 
+pub(crate) fn distance_sq(positions: [&[f32]; 3], i: usize, point: [f32; 3]) -> f32 {
+    let dx = positions[0][i] - point[0];
+    let dy = positions[1][i] - point[1];
+    let dz = positions[2][i] - point[2];
+    dx * dx + dy * dy + dz * dz
+}
+
+pub(crate) fn sort_by_distance(found: &mut [(f32, usize)]) {
+    found.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+}
+
+pub(crate) fn into_indices(found: Vec<(f32, usize)>) -> Vec<usize> {
+    found.into_iter().map(|(_, i)| i).collect()
+}
+
+/// Visits every cell whose Chebyshev distance from `center` is exactly `ring`.
+fn for_each_ring_cell(center: [i64; 3], ring: i64, mut visit: impl FnMut([i64; 3])) {
+    for dx in -ring..=ring {
+        for dy in -ring..=ring {
+            for dz in -ring..=ring {
+                if dx.abs().max(dy.abs()).max(dz.abs()) == ring {
+                    visit([center[0] + dx, center[1] + dy, center[2] + dz]);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the `k` closest candidates found so far are guaranteed to be the
+/// `k` nearest overall, given that every cell within `ring` of the query
+/// cell has been visited.
+fn nearest_settled(found: &mut [(f32, usize)], k: usize, ring: i64, cell_length: f32) -> bool {
+    if found.len() < k {
+        return false;
+    }
+    sort_by_distance(found);
+    let bound = ring as f32 * cell_length;
+    found[k - 1].0 <= bound * bound
+}
+
+/// Returns every particle within `radius` of `point`, nearest first, using a
+/// populated dense grid.
+pub fn query_radius(
+    grid: &Grid,
+    contents: &[Vec<usize>],
+    inv_h: f32,
+    positions: [&[f32]; 3],
+    point: [f32; 3],
+    radius: f32,
+) -> Vec<usize> {
+    let lo = get_cell(point[0] - radius, point[1] - radius, point[2] - radius, grid, inv_h);
+    let hi = get_cell(point[0] + radius, point[1] + radius, point[2] + radius, grid, inv_h);
+    let radius_sq = radius * radius;
+    let mut found = Vec::new();
+
+    for x in lo[0].max(0)..=hi[0].min(grid.count[0] as i32 - 1) {
+        for y in lo[1].max(0)..=hi[1].min(grid.count[1] as i32 - 1) {
+            for z in lo[2].max(0)..=hi[2].min(grid.count[2] as i32 - 1) {
+                for &i in &contents[hash(x, y, z, grid)] {
+                    let d2 = distance_sq(positions, i, point);
+                    if d2 <= radius_sq {
+                        found.push((d2, i));
+                    }
+                }
+            }
+        }
+    }
+
+    sort_by_distance(&mut found);
+    into_indices(found)
+}
+
+/// Returns the `k` particles nearest to `point`, nearest first, using a
+/// populated dense grid. Fewer are returned if the grid holds fewer than `k`.
+pub fn query_nearest(
+    grid: &Grid,
+    contents: &[Vec<usize>],
+    inv_h: f32,
+    positions: [&[f32]; 3],
+    point: [f32; 3],
+    k: usize,
+) -> Vec<usize> {
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let cell = get_cell(point[0], point[1], point[2], grid, inv_h);
+    let center = [cell[0] as i64, cell[1] as i64, cell[2] as i64];
+    let count = [grid.count[0] as i64, grid.count[1] as i64, grid.count[2] as i64];
+    let max_ring = (0..3)
+        .map(|d| center[d].abs().max((center[d] - (count[d] - 1)).abs()))
+        .max()
+        .unwrap_or(0);
+
+    let num_particles: usize = contents.iter().map(Vec::len).sum();
+    let mut found = Vec::new();
+    for ring in 0..=max_ring {
+        let cells_visited = (2 * ring + 1).pow(3) as usize;
+        if cells_visited > num_particles {
+            // A point far outside the grid would sweep many empty rings;
+            // scanning every binned particle is cheaper
+            found = contents.iter().flatten().map(|&i| (distance_sq(positions, i, point), i)).collect();
+            break;
+        }
+
+        for_each_ring_cell(center, ring, |c| {
+            let in_grid = (0..3).all(|d| c[d] >= 0 && c[d] < count[d]);
+            if in_grid {
+                for &i in &contents[hash(c[0] as i32, c[1] as i32, c[2] as i32, grid)] {
+                    found.push((distance_sq(positions, i, point), i));
+                }
+            }
+        });
+
+        if nearest_settled(&mut found, k, ring, 1.0 / inv_h) {
+            break;
+        }
+    }
+
+    sort_by_distance(&mut found);
+    found.truncate(k);
+    into_indices(found)
+}
+
+/// Selects the structure used to bin particles for neighbor finding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpatialIndex {
+    /// Dense grid covering the simulation box
+    Dense,
+    /// Sparse grid hashed into a fixed-size table, valid for unbounded domains
+    Hashed,
+    /// Kd-tree, which supports per-particle search radii
+    KdTree,
+}
+
+const HASH_PRIMES: [i64; 3] = [73_856_093, 19_349_663, 83_492_791];
+
+/// Uniform grid of unbounded extent whose cells are hashed into a fixed number
+/// of buckets. Colliding cells share a bucket, so each particle also records
+/// its cell coordinates to filter out false candidates.
+#[derive(Debug, Clone)]
+pub struct HashedGrid {
+    pub inv_cell_length: f32,
+    pub buckets: Vec<Vec<usize>>,
+    pub particle_cells: Vec<[i64; 3]>,
+}
+
+impl HashedGrid {
+    pub fn new(cell_length: f32, table_size: usize) -> Self {
+        HashedGrid {
+            inv_cell_length: 1.0 / cell_length,
+            buckets: vec![Vec::new(); table_size.max(1)],
+            particle_cells: Vec::new(),
+        }
+    }
+
+    pub fn cell(&self, x: f32, y: f32, z: f32) -> [i64; 3] {
+        // Float-to-int casts saturate, so escaped or non-finite positions
+        // still map to a valid cell. Clamping to the i32 range leaves room to
+        // step to adjacent cells without overflow.
+        [
+            (x * self.inv_cell_length).floor() as i32 as i64,
+            (y * self.inv_cell_length).floor() as i32 as i64,
+            (z * self.inv_cell_length).floor() as i32 as i64,
+        ]
+    }
+
+    pub fn bucket(&self, cell: [i64; 3]) -> usize {
+        let key = cell[0].wrapping_mul(HASH_PRIMES[0])
+            ^ cell[1].wrapping_mul(HASH_PRIMES[1])
+            ^ cell[2].wrapping_mul(HASH_PRIMES[2]);
+        key.rem_euclid(self.buckets.len() as i64) as usize
+    }
+
+    pub fn populate(&mut self, px: &[f32], py: &[f32], pz: &[f32], num_particles: usize) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.clear();
+        }
+        self.particle_cells.resize(num_particles, [0; 3]);
+
+        for i in 0..num_particles {
+            let cell = self.cell(px[i], py[i], pz[i]);
+            let bucket = self.bucket(cell);
+            self.buckets[bucket].push(i);
+            self.particle_cells[i] = cell;
+        }
+    }
+
+    /// Produces the same pairs as `find_neighbors` on a dense grid: every
+    /// `j > i` in a cell adjacent to the cell of `i`, in ascending order.
+    pub fn find_neighbors(&self, neighbors: &mut [Vec<usize>]) {
+        for (i, neighbor_list) in neighbors.iter_mut().enumerate() {
+            neighbor_list.clear();
+            let cell = self.particle_cells[i];
+
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let neighbor_cell = [cell[0] + dx, cell[1] + dy, cell[2] + dz];
+
+                        for &j in &self.buckets[self.bucket(neighbor_cell)] {
+                            if j > i && self.particle_cells[j] == neighbor_cell {
+                                neighbor_list.push(j);
+                            }
+                        }
+                    }
+                }
+            }
+
+            neighbor_list.sort_unstable();
+        }
+    }
+    /// Returns every particle within `radius` of `point`, nearest first.
+    pub fn query_radius(&self, positions: [&[f32]; 3], point: [f32; 3], radius: f32) -> Vec<usize> {
+        let lo = self.cell(point[0] - radius, point[1] - radius, point[2] - radius);
+        let hi = self.cell(point[0] + radius, point[1] + radius, point[2] + radius);
+        let radius_sq = radius * radius;
+        let mut found = Vec::new();
+
+        let cells_in_range = (0..3).fold(1_u128, |acc, d| acc * (hi[d] - lo[d] + 1) as u128);
+        if cells_in_range > self.particle_cells.len() as u128 {
+            // Scanning every particle is cheaper than visiting the cells
+            for i in 0..self.particle_cells.len() {
+                let d2 = distance_sq(positions, i, point);
+                if d2 <= radius_sq {
+                    found.push((d2, i));
+                }
+            }
+        } else {
+            for x in lo[0]..=hi[0] {
+                for y in lo[1]..=hi[1] {
+                    for z in lo[2]..=hi[2] {
+                        self.collect_cell([x, y, z], positions, point, radius_sq, &mut found);
+                    }
+                }
+            }
+        }
+
+        sort_by_distance(&mut found);
+        into_indices(found)
+    }
+
+    /// Returns the `k` particles nearest to `point`, nearest first.
+    pub fn query_nearest(&self, positions: [&[f32]; 3], point: [f32; 3], k: usize) -> Vec<usize> {
+        let num_particles = self.particle_cells.len();
+        if k == 0 || num_particles == 0 {
+            return Vec::new();
+        }
+
+        let center = self.cell(point[0], point[1], point[2]);
+        let cell_length = 1.0 / self.inv_cell_length;
+        let mut found = Vec::new();
+        let mut ring = 0;
+
+        loop {
+            for_each_ring_cell(center, ring, |c| {
+                self.collect_cell(c, positions, point, f32::INFINITY, &mut found);
+            });
+
+            if found.len() == num_particles || nearest_settled(&mut found, k, ring, cell_length) {
+                break;
+            }
+
+            ring += 1;
+            let cells_visited = (2 * ring + 1).pow(3) as usize;
+            if cells_visited > num_particles {
+                // Remaining particles are sparse; scan them all instead
+                found = (0..num_particles)
+                    .map(|i| (distance_sq(positions, i, point), i))
+                    .collect();
+                break;
+            }
+        }
+
+        sort_by_distance(&mut found);
+        found.truncate(k);
+        into_indices(found)
+    }
+
+    fn collect_cell(
+        &self,
+        cell: [i64; 3],
+        positions: [&[f32]; 3],
+        point: [f32; 3],
+        radius_sq: f32,
+        found: &mut Vec<(f32, usize)>,
+    ) {
+        for &i in &self.buckets[self.bucket(cell)] {
+            if self.particle_cells[i] == cell {
+                let d2 = distance_sq(positions, i, point);
+                if d2 <= radius_sq {
+                    found.push((d2, i));
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::scattered_points;
+
+    const PLANE: [[f32; 2]; 3] = [[-2.0, 2.0], [-2.0, 2.0], [0.0, 0.0]];
 
     #[test]
     fn compute_grid_1d_simple_case() {
@@ -170,6 +472,7 @@ mod tests {
         let cell_length = 1.5;
         let grid = compute_grid(extents, cell_length);
 
+        #[allow(clippy::needless_range_loop)]
         for dim in 0..extents.len() {
             let grid_min = grid.offset[dim];
             let grid_max = grid.offset[dim] + grid.count[dim] as f32 * cell_length;
@@ -348,4 +651,162 @@ mod tests {
         
         assert_eq!(all_pairs, vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)]);
     }
-}
+
+    fn dense_and_hashed_neighbors(px: &[f32], py: &[f32], pz: &[f32], table_size: usize) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let num_particles = px.len();
+        let smoothing_radius = 1.0;
+        let inv_h = 1.0 / smoothing_radius;
+
+        // Cell boundaries of this grid fall on integers, like the hashed grid
+        let grid = compute_grid(&[&[-2.5_f32, 2.5_f32], &[-2.5_f32, 2.5_f32], &[0.0_f32, 0.0_f32]], smoothing_radius);
+        let n_cells = grid.count.iter().product();
+        let mut cell_contents: Vec<Vec<usize>> = vec![vec![]; n_cells];
+        let mut point_to_cell = vec![0; num_particles];
+        populate_grid(px, py, pz, &grid, &mut cell_contents, &mut point_to_cell, num_particles, inv_h);
+        let mut dense: Vec<Vec<usize>> = vec![vec![]; num_particles];
+        find_neighbors(&grid, &cell_contents, &mut dense);
+        for list in dense.iter_mut() {
+            list.sort_unstable();
+        }
+
+        let mut hashed_grid = HashedGrid::new(smoothing_radius, table_size);
+        hashed_grid.populate(px, py, pz, num_particles);
+        let mut hashed: Vec<Vec<usize>> = vec![vec![]; num_particles];
+        hashed_grid.find_neighbors(&mut hashed);
+
+        (dense, hashed)
+    }
+
+    #[test]
+    fn hashed_grid_matches_dense_grid() {
+        let px = [0.0, 3.0, 0.5, 3.5, 0.9, 1.1, -0.5, 2.2];
+        let py = [0.0, 3.0, 0.5, 3.5, 1.1, 0.9, 1.8, -1.3];
+        let pz = [0.0; 8];
+
+        let (dense, hashed) = dense_and_hashed_neighbors(&px, &py, &pz, 64);
+        assert_eq!(dense, hashed);
+    }
+
+    #[test]
+    fn hashed_grid_filters_bucket_collisions() {
+        let px = [0.0, 3.0, 0.5, 3.5, 0.9, 1.1, -0.5, 2.2];
+        let py = [0.0, 3.0, 0.5, 3.5, 1.1, 0.9, 1.8, -1.3];
+        let pz = [0.0; 8];
+
+        // A single bucket forces every cell to collide
+        let (dense, hashed) = dense_and_hashed_neighbors(&px, &py, &pz, 1);
+        assert_eq!(dense, hashed);
+    }
+
+    #[test]
+    fn hashed_grid_handles_escaped_particles() {
+        let px = [-1.0e6, -1.0e6 + 0.5, f32::MAX, f32::NEG_INFINITY, f32::NAN, 0.2];
+        let py = [-3.5, -3.2, 0.0, 0.0, 0.0, 0.1];
+        let pz = [0.0; 6];
+
+        let mut hashed_grid = HashedGrid::new(1.0, 16);
+        hashed_grid.populate(&px, &py, &pz, px.len());
+        let mut neighbors: Vec<Vec<usize>> = vec![vec![]; px.len()];
+        hashed_grid.find_neighbors(&mut neighbors);
+
+        assert_eq!(neighbors[0], vec![1]);
+        assert_eq!(neighbors[2], vec![]);
+        assert_eq!(neighbors[3], vec![]);
+    }
+
+    fn brute_force_nearest(positions: [&[f32]; 3], point: [f32; 3], k: usize) -> Vec<usize> {
+        let mut all: Vec<(f32, usize)> = (0..positions[0].len())
+            .map(|i| (distance_sq(positions, i, point), i))
+            .collect();
+        sort_by_distance(&mut all);
+        all.truncate(k);
+        into_indices(all)
+    }
+
+    fn brute_force_radius(positions: [&[f32]; 3], point: [f32; 3], radius: f32) -> Vec<usize> {
+        let mut all: Vec<(f32, usize)> = (0..positions[0].len())
+            .map(|i| (distance_sq(positions, i, point), i))
+            .filter(|&(d2, _)| d2 <= radius * radius)
+            .collect();
+        sort_by_distance(&mut all);
+        into_indices(all)
+    }
+
+    const QUERY_POINTS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.3, -0.7, 0.0], [-1.9, 1.95, 0.0], [6.0, -5.0, 0.0]];
+
+    #[test]
+    fn dense_queries_match_brute_force() {
+        let (px, py, pz) = scattered_points(200, 7, PLANE);
+        let positions = [&px[..], &py[..], &pz[..]];
+        let smoothing_radius = 0.5;
+        let inv_h = 1.0 / smoothing_radius;
+
+        let grid = compute_grid(&[&[-2.0_f32, 2.0_f32], &[-2.0_f32, 2.0_f32], &[0.0_f32, 0.0_f32]], smoothing_radius);
+        let n_cells = grid.count.iter().product();
+        let mut cell_contents: Vec<Vec<usize>> = vec![vec![]; n_cells];
+        let mut point_to_cell = vec![0; px.len()];
+        populate_grid(&px, &py, &pz, &grid, &mut cell_contents, &mut point_to_cell, px.len(), inv_h);
+
+        for point in QUERY_POINTS {
+            for radius in [0.1, 0.5, 1.3] {
+                assert_eq!(
+                    query_radius(&grid, &cell_contents, inv_h, positions, point, radius),
+                    brute_force_radius(positions, point, radius),
+                );
+            }
+            for k in [0, 1, 5, 40, 250] {
+                assert_eq!(
+                    query_nearest(&grid, &cell_contents, inv_h, positions, point, k),
+                    brute_force_nearest(positions, point, k),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dense_nearest_to_a_far_point_matches_brute_force() {
+        let (px, py, pz) = scattered_points(200, 5, PLANE);
+        let positions = [&px[..], &py[..], &pz[..]];
+        let inv_h = 2.0;
+
+        let grid = compute_grid(&[&[-2.0_f32, 2.0_f32], &[-2.0_f32, 2.0_f32], &[0.0_f32, 0.0_f32]], 0.5);
+        let mut cell_contents: Vec<Vec<usize>> = vec![vec![]; grid.count.iter().product()];
+        let mut point_to_cell = vec![0; px.len()];
+        populate_grid(&px, &py, &pz, &grid, &mut cell_contents, &mut point_to_cell, px.len(), inv_h);
+
+        // Millions of rings lie between these points and the grid
+        for point in [[1.0e6, -1.0e6, 0.0], [-3.0e7, 0.0, 2.0e6]] {
+            assert_eq!(
+                query_nearest(&grid, &cell_contents, inv_h, positions, point, 3),
+                brute_force_nearest(positions, point, 3),
+            );
+        }
+    }
+
+    #[test]
+    fn hashed_queries_match_brute_force() {
+        let (mut px, mut py, pz) = scattered_points(200, 11, PLANE);
+        // A few escaped particles far outside the original box
+        px[3] = 500.0;
+        py[17] = -1.0e5;
+        let positions = [&px[..], &py[..], &pz[..]];
+
+        let mut hashed_grid = HashedGrid::new(0.5, 128);
+        hashed_grid.populate(&px, &py, &pz, px.len());
+
+        for point in QUERY_POINTS {
+            for radius in [0.1, 0.5, 1.3, 1.0e6] {
+                assert_eq!(
+                    hashed_grid.query_radius(positions, point, radius),
+                    brute_force_radius(positions, point, radius),
+                );
+            }
+            for k in [0, 1, 5, 40, 250] {
+                assert_eq!(
+                    hashed_grid.query_nearest(positions, point, k),
+                    brute_force_nearest(positions, point, k),
+                );
+            }
+        }
+    }
+}
\ No newline at end of file
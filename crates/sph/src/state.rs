@@ -1,6 +1,13 @@
-use crate::constants::N;
+use crate::constants::{GLOBALS, N};
 use crate::initial_conditions::fill_state;
-use crate::spatial_hash::Grid;
+use crate::spatial_hash::{query_nearest, query_radius, Grid, HashedGrid, SpatialIndex};
+use crate::verlet::VerletList;
+use crate::kd_tree::KdTree;
+use crate::self_gravity::BarnesHutTree;
+use crate::diagnostics::Diagnostics;
+use crate::material::MaterialRegistry;
+use crate::heat::ThermalWall;
+use crate::elasticity::{Matrix3, ReferenceConfiguration, IDENTITY};
 
 pub struct State {
     pub x: [f32; N],
@@ -17,18 +24,41 @@ pub struct State {
     pub az_: [f32; N],
     pub rho: [f32; N],
     pub p: [f32; N],
+    pub drho: [f32; N],
+    pub h: [f32; N],
+    pub omega: [f32; N],
+    pub u: [f32; N],
+    pub du: [f32; N],
+    pub du_: [f32; N],
+    pub material: [usize; N],
+    pub shear_rate: [f32; N],
+    pub viscosity: [f32; N],
+    pub deformation_gradient: [Matrix3; N],
+    pub stress: [Matrix3; N],
+    pub kernel_correction: [Matrix3; N],
+    pub free_surface: [bool; N],
+    pub surface_normal: [[f32; 3]; N],
+    pub vorticity: [[f32; 3]; N],
+    pub temperature: [f32; N],
+    pub dtemperature: [f32; N],
     pub grid: Grid,
+    pub hashed_grid: HashedGrid,
+    pub kd_tree: KdTree,
+    pub gravity_tree: BarnesHutTree,
     pub cell_contents: Vec<Vec<usize>>,
     pub point_to_cell: Vec<usize>,
     pub neighbors: Vec<Vec<usize>>,
-    pub particle_mass: f32,
     pub inv_h: f32,
     pub neighbor_offsets: Vec<usize>,
-    pub inv_reference_density: f32,
-    pub tait_b: f32,
+    pub materials: MaterialRegistry,
+    pub thermal_walls: Vec<ThermalWall>,
+    pub reference: ReferenceConfiguration,
+    pub verlet: VerletList,
+    pub diagnostics: Diagnostics,
 }
 
 impl State {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let mut state = State {
             x: [0.0; N],
@@ -45,20 +75,43 @@ impl State {
             az_: [0.0; N],
             rho: [0.0; N],
             p: [0.0; N],
+            drho: [0.0; N],
+            h: [0.0; N],
+            omega: [0.0; N],
+            u: [0.0; N],
+            du: [0.0; N],
+            du_: [0.0; N],
+            material: [0; N],
+            shear_rate: [0.0; N],
+            viscosity: [0.0; N],
+            deformation_gradient: [IDENTITY; N],
+            stress: [[[0.0; 3]; 3]; N],
+            kernel_correction: [IDENTITY; N],
+            free_surface: [false; N],
+            surface_normal: [[0.0; 3]; N],
+            vorticity: [[0.0; 3]; N],
+            temperature: [0.0; N],
+            dtemperature: [0.0; N],
             grid: Grid { count: Vec::new(), offset: Vec::new() },
+            hashed_grid: HashedGrid::new(1.0, 1),
+            kd_tree: KdTree::default(),
+            gravity_tree: BarnesHutTree::default(),
             cell_contents: Vec::new(),
             point_to_cell: vec![0; N],
             neighbors: vec![Vec::new(); N],
-            particle_mass: 0.0,
             inv_h: 0.0,
             neighbor_offsets: Vec::new(),
-            inv_reference_density: 0.0,
-            tait_b: 0.0,
+            materials: MaterialRegistry::default(),
+            thermal_walls: Vec::new(),
+            reference: ReferenceConfiguration::default(),
+            verlet: VerletList::new(0.0, N),
+            diagnostics: Diagnostics::default(),
         };
         fill_state(&mut state);
         state
     }
 
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         N * 14
     }
@@ -66,4 +119,37 @@ impl State {
     pub fn ptr(&self) -> *const f32 {
         self.x.as_ptr()
     }
-}
\ No newline at end of file
+
+    /// Particles within `radius` of `point`, nearest first. Distances use the
+    /// current positions, but the spatial index is the one built at the start
+    /// of the last update, before the leapfrog drift moved the particles. A
+    /// particle that has since left its bin can be missed, up to its
+    /// displacement over the last step, or up to half the skin with Verlet
+    /// lists enabled.
+    pub fn particles_within(&self, point: [f32; 3], radius: f32) -> Vec<usize> {
+        let positions = [&self.x[..], &self.y[..], &self.z[..]];
+        match GLOBALS.spatial_index {
+            SpatialIndex::Dense => {
+                let inv_cell_length = 1.0 / GLOBALS.neighbor_search_radius() as f32;
+                query_radius(&self.grid, &self.cell_contents, inv_cell_length, positions, point, radius)
+            }
+            SpatialIndex::Hashed => self.hashed_grid.query_radius(positions, point, radius),
+            SpatialIndex::KdTree => self.kd_tree.query_radius(positions, point, radius),
+        }
+    }
+
+    /// The `k` particles nearest to `point`, nearest first. The index is as
+    /// stale as in [`State::particles_within`], so a particle that drifted
+    /// into range since the last update can be passed over for a farther one.
+    pub fn nearest_particles(&self, point: [f32; 3], k: usize) -> Vec<usize> {
+        let positions = [&self.x[..], &self.y[..], &self.z[..]];
+        match GLOBALS.spatial_index {
+            SpatialIndex::Dense => {
+                let inv_cell_length = 1.0 / GLOBALS.neighbor_search_radius() as f32;
+                query_nearest(&self.grid, &self.cell_contents, inv_cell_length, positions, point, k)
+            }
+            SpatialIndex::Hashed => self.hashed_grid.query_nearest(positions, point, k),
+            SpatialIndex::KdTree => self.kd_tree.query_nearest(positions, point, k),
+        }
+    }
+}
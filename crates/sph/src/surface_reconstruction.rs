@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::constants::GLOBALS;
+use crate::eulerian_grid::{box_grid, particle_index, FieldGrid, GridParticles};
+use crate::kernel::kernel_nd;
+use crate::material::Material;
+use crate::spatial_hash::HashedGrid;
+use crate::state::State;
+
+/// Color field value taken as the fluid surface: half of the interior value.
+pub const SURFACE_ISO_LEVEL: f32 = 0.5;
+
+/// Cube edges as pairs of corners, which are numbered `dx + 2 dy + 4 dz`.
+const CUBE_EDGES: [[usize; 2]; 12] = [
+    [0, 1], [2, 3], [4, 5], [6, 7],
+    [0, 2], [1, 3], [4, 6], [5, 7],
+    [0, 4], [1, 5], [2, 6], [3, 7],
+];
+
+/// Cube faces as their corners in counterclockwise order seen from outside.
+const CUBE_FACES: [[usize; 4]; 6] = [[0, 4, 6, 2], [1, 3, 7, 5], [0, 1, 5, 4], [2, 6, 7, 3], [0, 2, 3, 1], [4, 5, 7, 6]];
+
+/// Triangles sharing vertices, wound counterclockwise seen from outside.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TriangleMesh {
+    pub vertices: Vec<[f32; 3]>,
+    pub triangles: Vec<[usize; 3]>,
+}
+
+/// Color field `c(x) = sum_j V_j W(x - x_j)` of the mobile particles at every
+/// cell center. It is close to 1 inside the fluid and falls to 0 over one
+/// smoothing radius outside it.
+pub fn color_field(
+    particles: &GridParticles,
+    materials: &[Material],
+    index: &HashedGrid,
+    smoothing_radius: f32,
+    dim: usize,
+    grid: &mut FieldGrid,
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+
+    for k in 0..grid.values.len() {
+        let point = grid.center(k);
+        grid.values[k] = 0.0;
+        for j in index.query_radius(particles.position, point, smoothing_radius) {
+            let material = &materials[particles.material[j]];
+            if !material.is_mobile() || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [point[0] - x[j], point[1] - y[j], point[2] - z[j]];
+            let d = (dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2]).sqrt();
+            grid.values[k] += material.mass / rho[j] * kernel_nd(d as f64, inv_h, dim) as f32;
+        }
+    }
+}
+
+/// Color field of the current particles on a grid of `resolution` cells per
+/// axis covering the simulation box.
+pub fn color_field_grid(state: &State, resolution: usize) -> FieldGrid {
+    let index = particle_index(state);
+    let mut grid = box_grid(resolution);
+    let particles = GridParticles {
+        position: [&state.x, &state.y, &state.z],
+        rho: &state.rho,
+        material: &state.material,
+    };
+    color_field(&particles, state.materials.as_slice(), &index, GLOBALS.smoothing_radius as f32, GLOBALS.dim, &mut grid);
+    grid
+}
+
+/// Points where the iso-surface crosses grid edges, shared by every cell
+/// that touches the edge.
+#[derive(Default)]
+struct EdgeVertices {
+    index: HashMap<(usize, usize), usize>,
+    positions: Vec<[f32; 3]>,
+}
+
+impl EdgeVertices {
+    /// Vertex on the edge between the samples `a` and `b`, which lie on
+    /// opposite sides of `iso`, placed by linear interpolation.
+    fn on_edge(&mut self, grid: &FieldGrid, a: usize, b: usize, iso: f32) -> usize {
+        let positions = &mut self.positions;
+        *self.index.entry((a.min(b), a.max(b))).or_insert_with(|| {
+            let (value_a, value_b) = (grid.values[a], grid.values[b]);
+            let t = ((iso - value_a) / (value_b - value_a)).clamp(0.0, 1.0);
+            let (p, q) = (grid.center(a), grid.center(b));
+            positions.push(std::array::from_fn(|c| p[c] + t * (q[c] - p[c])));
+            positions.len() - 1
+        })
+    }
+}
+
+/// Contour lines of the lowest z layer of `grid` at `iso`. Lines that close
+/// on themselves end with their first point; the others end at the edge of
+/// the grid. Ambiguous cells, with diagonal corners on the same side, are
+/// resolved with the mean of their four corners.
+pub fn marching_squares(grid: &FieldGrid, iso: f32) -> Vec<Vec<[f32; 2]>> {
+    // Cell edges as pairs of corners, in order around the cell
+    const EDGES: [[usize; 2]; 4] = [[0, 1], [1, 2], [3, 2], [0, 3]];
+
+    let [nx, ny, _] = grid.resolution;
+    let mut vertices = EdgeVertices::default();
+    let mut segments = Vec::new();
+    for j in 0..ny.saturating_sub(1) {
+        for i in 0..nx.saturating_sub(1) {
+            let corners = [grid.index([i, j, 0]), grid.index([i + 1, j, 0]), grid.index([i + 1, j + 1, 0]), grid.index([i, j + 1, 0])];
+            let inside = corners.map(|c| grid.values[c] > iso);
+            let crossed: Vec<usize> = (0..4).filter(|&e| inside[EDGES[e][0]] != inside[EDGES[e][1]]).collect();
+
+            let pairs = match crossed.len() {
+                2 => vec![[crossed[0], crossed[1]]],
+                4 => {
+                    // Cut off the two corners on the other side from the center
+                    let center_inside = corners.iter().map(|&c| grid.values[c]).sum::<f32>() / 4.0 > iso;
+                    if center_inside == inside[0] {
+                        vec![[0, 1], [2, 3]]
+                    } else {
+                        vec![[3, 0], [1, 2]]
+                    }
+                }
+                _ => Vec::new(),
+            };
+            for edges in pairs {
+                segments.push(edges.map(|e| vertices.on_edge(grid, corners[EDGES[e][0]], corners[EDGES[e][1]], iso)));
+            }
+        }
+    }
+
+    chain(&segments, vertices.positions.len())
+        .into_iter()
+        .map(|line| line.into_iter().map(|v| [vertices.positions[v][0], vertices.positions[v][1]]).collect())
+        .collect()
+}
+
+/// Joins segments that share vertices into lines of vertex indices. Every
+/// vertex belongs to at most two segments.
+fn chain(segments: &[[usize; 2]], num_vertices: usize) -> Vec<Vec<usize>> {
+    let mut incident = vec![Vec::new(); num_vertices];
+    for (s, segment) in segments.iter().enumerate() {
+        for &v in segment {
+            incident[v].push(s);
+        }
+    }
+
+    let mut used = vec![false; segments.len()];
+    let mut lines = Vec::new();
+    // Open lines have to start at one of their ends, so try those first
+    let ends = (0..num_vertices).filter(|&v| incident[v].len() == 1);
+    for start in ends.chain(0..num_vertices) {
+        while let Some(&first) = incident[start].iter().find(|&&s| !used[s]) {
+            let mut line = vec![start];
+            let (mut vertex, mut segment) = (start, first);
+            loop {
+                used[segment] = true;
+                let [a, b] = segments[segment];
+                vertex = if a == vertex { b } else { a };
+                line.push(vertex);
+                match incident[vertex].iter().find(|&&s| !used[s]) {
+                    Some(&next) => segment = next,
+                    None => break,
+                }
+            }
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Triangle mesh of the iso-surface of `grid` at `iso` by marching cubes.
+/// A face with diagonal corners on the same side is resolved with the mean
+/// of its four corners, as in `marching_squares`; both cells sharing the
+/// face see the same mean, so surfaces that stay inside the grid are closed.
+/// Vertices lie on grid edges, apart from the centers of the few loops that
+/// cannot be fanned from one of their own vertices.
+pub fn marching_cubes(grid: &FieldGrid, iso: f32) -> TriangleMesh {
+    let [nx, ny, nz] = grid.resolution;
+    let cases = cube_cases();
+    let mut vertices = EdgeVertices::default();
+    let mut triangles = Vec::new();
+
+    for k in 0..nz.saturating_sub(1) {
+        for j in 0..ny.saturating_sub(1) {
+            for i in 0..nx.saturating_sub(1) {
+                let corners: [usize; 8] = std::array::from_fn(|c| grid.index([i + (c & 1), j + (c >> 1 & 1), k + (c >> 2 & 1)]));
+                let values = corners.map(|c| grid.values[c]);
+                let inside = (0..8).filter(|&c| values[c] > iso).fold(0, |mask, c| mask | 1 << c);
+                let joined = (0..6)
+                    .filter(|&f| ambiguous_face(inside, f) && CUBE_FACES[f].iter().map(|&c| values[c]).sum::<f32>() / 4.0 > iso)
+                    .fold(0, |mask, f| mask | 1 << f);
+
+                for (polygon, fan) in &cases[inside | joined << 8].polygons {
+                    let ids: Vec<usize> = polygon
+                        .iter()
+                        .map(|&e| vertices.on_edge(grid, corners[CUBE_EDGES[e][0]], corners[CUBE_EDGES[e][1]], iso))
+                        .collect();
+                    match *fan {
+                        Fan::Corner(apex) => {
+                            let n = ids.len();
+                            for v in 1..n - 1 {
+                                triangles.push([ids[apex], ids[(apex + v) % n], ids[(apex + v + 1) % n]]);
+                            }
+                        }
+                        Fan::Center => {
+                            let center = ids.iter().fold([0.0; 3], |sum, &v| {
+                                let q = vertices.positions[v];
+                                std::array::from_fn(|c| sum[c] + q[c] / ids.len() as f32)
+                            });
+                            vertices.positions.push(center);
+                            let center = vertices.positions.len() - 1;
+                            for v in 0..ids.len() {
+                                triangles.push([center, ids[v], ids[(v + 1) % ids.len()]]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    TriangleMesh { vertices: vertices.positions, triangles }
+}
+
+/// Whether face `f` has its diagonal corners on the same side and the other
+/// pair on the other side, given the corners in `inside`.
+fn ambiguous_face(inside: usize, f: usize) -> bool {
+    let high = CUBE_FACES[f].map(|c| inside >> c & 1 == 1);
+    high[0] == high[2] && high[1] == high[3] && high[0] != high[1]
+}
+
+/// How the loop of one polygon is cut into triangles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fan {
+    /// Fan from the vertex at this position of the loop
+    Corner(usize),
+    /// Fan from an added vertex at the center of the loop, for loops whose
+    /// every fan would lay a diagonal on a cube face, where the neighboring
+    /// cell may lay the same one
+    Center,
+}
+
+/// One case of marching cubes: closed loops of crossed cube edges, wound
+/// counterclockwise seen from the low side, and how to fan each of them.
+#[derive(Debug, Clone, Default)]
+struct CubeCase {
+    polygons: Vec<(Vec<usize>, Fan)>,
+}
+
+/// Case table of marching cubes for every set of corners above the level
+/// (low 8 bits) and set of ambiguous faces whose corners above the level are
+/// joined (high 6 bits). It is built once from the corner signs rather than
+/// written out by hand.
+fn cube_cases() -> &'static [CubeCase] {
+    static CASES: OnceLock<Vec<CubeCase>> = OnceLock::new();
+    CASES.get_or_init(|| (0..1 << 14).map(|case| cube_case(case & 0xff, case >> 8)).collect())
+}
+
+/// One case of the table. On each face the contour runs from an edge
+/// leaving the corners above the level to one entering them, going
+/// counterclockwise around them seen from outside the cube. Chaining those
+/// segments gives closed loops, which are reversed to face the low side.
+fn cube_case(inside: usize, joined: usize) -> CubeCase {
+    let high = |c: usize| inside >> c & 1 == 1;
+    let edge = |a: usize, b: usize| CUBE_EDGES.iter().position(|&[p, q]| (p, q) == (a.min(b), a.max(b))).unwrap();
+
+    let mut next = [None; 12];
+    for (f, face) in CUBE_FACES.iter().enumerate() {
+        let exits = (0..4).filter(|&k| high(face[k]) && !high(face[(k + 1) % 4]));
+        let entries: Vec<usize> = (0..4).filter(|&k| !high(face[k]) && high(face[(k + 1) % 4])).collect();
+        for k in exits {
+            let entry = match entries.as_slice() {
+                [entry] => *entry,
+                // Go around the low corner after this edge, or cut off the
+                // high corner before it
+                _ if joined >> f & 1 == 1 => (k + 1) % 4,
+                _ => (k + 3) % 4,
+            };
+            next[edge(face[k], face[(k + 1) % 4])] = Some(edge(face[entry], face[(entry + 1) % 4]));
+        }
+    }
+
+    let mut visited = [false; 12];
+    let mut case = CubeCase::default();
+    for start in 0..12 {
+        if visited[start] || next[start].is_none() {
+            continue;
+        }
+        let mut polygon = Vec::new();
+        let mut e = start;
+        while !visited[e] {
+            visited[e] = true;
+            polygon.push(e);
+            e = next[e].unwrap();
+        }
+        polygon.reverse();
+
+        let n = polygon.len();
+        let fan = (0..n)
+            .find(|&apex| (2..n - 1).all(|v| !on_one_face(polygon[apex], polygon[(apex + v) % n])))
+            .map_or(Fan::Center, Fan::Corner);
+        case.polygons.push((polygon, fan));
+    }
+    case
+}
+
+/// Whether two cube edges lie on a common face.
+fn on_one_face(a: usize, b: usize) -> bool {
+    CUBE_FACES
+        .iter()
+        .any(|face| CUBE_EDGES[a].iter().chain(&CUBE_EDGES[b]).all(|c| face.contains(c)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{random_numbers, square_lattice, water, SMOOTHING_RADIUS, SPACING};
+
+    /// Grid over `[-1, 1]^dim` sampling `f`.
+    fn sampled(dim: usize, resolution: usize, f: impl Fn([f32; 3]) -> f32) -> FieldGrid {
+        let (min, max, cells) = if dim == 3 {
+            ([-1.0; 3], [1.0; 3], [resolution; 3])
+        } else {
+            ([-1.0, -1.0, 0.0], [1.0, 1.0, 0.0], [resolution, resolution, 1])
+        };
+        let mut grid = FieldGrid::new(min, max, cells);
+        for k in 0..grid.values.len() {
+            grid.values[k] = f(grid.center(k));
+        }
+        grid
+    }
+
+    fn radius(p: [f32; 3]) -> f32 {
+        (p[0] * p[0] + p[1] * p[1] + p[2] * p[2]).sqrt()
+    }
+
+    #[test]
+    fn circle_is_one_closed_line() {
+        let grid = sampled(2, 20, |p| 1.0 - radius(p));
+        let lines = marching_squares(&grid, 0.5);
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert!(line.len() > 20);
+        assert_eq!(line.first(), line.last());
+        for p in line {
+            assert!((radius([p[0], p[1], 0.0]) - 0.5).abs() < 0.02, "{p:?}");
+        }
+    }
+
+    #[test]
+    fn straight_line_runs_across_the_grid() {
+        let grid = sampled(2, 20, |p| p[0] + 0.5);
+        let lines = marching_squares(&grid, 0.5);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].len(), 20);
+        assert!(lines[0].iter().all(|p| p[0].abs() < 1e-6), "{:?}", lines[0]);
+        let ends = [lines[0][0][1], lines[0][19][1]];
+        assert!((ends[0] + ends[1]).abs() < 1e-6 && (ends[0].abs() - 0.95).abs() < 1e-6, "{ends:?}");
+    }
+
+    #[test]
+    fn saddle_follows_the_cell_mean() {
+        let mut grid = FieldGrid::new([0.0; 3], [2.0, 2.0, 0.0], [2, 2, 1]);
+        grid.values = vec![1.0, 0.0, 0.0, 0.8];
+        // The mean is below the level, so the two corners above it are cut off
+        let lines = marching_squares(&grid, 0.5);
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert_eq!(line.len(), 2);
+            let corner = if line[0][0] + line[0][1] < 2.0 { 0.5 } else { 1.5 };
+            assert!(line.iter().all(|p| (p[0] - corner).abs() <= 0.5 && (p[1] - corner).abs() <= 0.5), "{line:?}");
+        }
+    }
+
+    /// Checks that every edge of `mesh` is crossed once in each direction.
+    fn assert_closed(mesh: &TriangleMesh) {
+        let mut edges = HashMap::new();
+        for t in &mesh.triangles {
+            for (a, b) in [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])] {
+                *edges.entry((a, b)).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in &edges {
+            assert_eq!(count, 1, "{a} {b} {:?}", mesh.triangles.iter().filter(|t| t.contains(&a) && t.contains(&b)).collect::<Vec<_>>());
+            assert_eq!(edges.get(&(b, a)), Some(&1));
+        }
+    }
+
+    /// Number of connected pieces of `mesh`.
+    fn pieces(mesh: &TriangleMesh) -> usize {
+        let mut piece: Vec<usize> = (0..mesh.vertices.len()).collect();
+        fn root(piece: &mut [usize], mut v: usize) -> usize {
+            while piece[v] != v {
+                v = piece[v];
+            }
+            v
+        }
+        for t in &mesh.triangles {
+            for v in [t[1], t[2]] {
+                let (a, b) = (root(&mut piece, t[0]), root(&mut piece, v));
+                piece[a] = b;
+            }
+        }
+        (0..piece.len()).filter(|&v| root(&mut piece, v) == v).count()
+    }
+
+    #[test]
+    fn sphere_mesh_is_closed_and_outward() {
+        let grid = sampled(3, 20, |p| 1.0 - radius(p));
+        let mesh = marching_cubes(&grid, 0.5);
+        for &p in &mesh.vertices {
+            assert!((radius(p) - 0.5).abs() < 0.02, "{p:?}");
+        }
+
+        assert_closed(&mesh);
+
+        // Outward winding gives a positive enclosed volume
+        let volume: f32 = mesh
+            .triangles
+            .iter()
+            .map(|t| {
+                let [p, q, r] = t.map(|v| mesh.vertices[v]);
+                (p[0] * (q[1] * r[2] - q[2] * r[1]) + p[1] * (q[2] * r[0] - q[0] * r[2]) + p[2] * (q[0] * r[1] - q[1] * r[0])) / 6.0
+            })
+            .sum();
+        let exact = 4.0 / 3.0 * std::f32::consts::PI * 0.125;
+        assert!((volume - exact).abs() < 0.05 * exact, "{volume} {exact}");
+    }
+
+    #[test]
+    fn noise_mesh_is_closed() {
+        // Random values in the interior give many ambiguous faces
+        let mut next = random_numbers(3);
+        let mut grid = FieldGrid::new([0.0; 3], [1.0; 3], [10; 3]);
+        for k in 0..grid.values.len() {
+            let node = [k % 10, k / 10 % 10, k / 100];
+            let interior = node.iter().all(|&n| n > 0 && n < 9);
+            grid.values[k] = if interior { next() } else { 0.0 };
+        }
+        let mesh = marching_cubes(&grid, 0.5);
+        assert!(mesh.triangles.len() > 100);
+        assert_closed(&mesh);
+    }
+
+    #[test]
+    fn ambiguous_face_follows_its_mean() {
+        // Two corners above the level sit diagonally on one face of the
+        // middle cell of a 4^3 grid
+        let blobs = |low: f32| {
+            let mut grid = FieldGrid::new([0.0; 3], [1.0; 3], [4; 3]);
+            for k in 0..grid.values.len() {
+                let node = [k % 4, k / 4 % 4, k / 16];
+                let interior = node.iter().all(|&n| n == 1 || n == 2);
+                grid.values[k] = match node {
+                    [1, 1, 1] | [2, 2, 1] => 0.9,
+                    _ if interior => low,
+                    _ => 0.0,
+                };
+            }
+            let mesh = marching_cubes(&grid, 0.5);
+            assert_closed(&mesh);
+            pieces(&mesh)
+        };
+        // A face mean above the level joins them, one below keeps them apart
+        assert_eq!(blobs(0.3), 1);
+        assert_eq!(blobs(0.0), 2);
+    }
+
+    #[test]
+    fn block_outline_follows_its_particles() {
+        // Square block of particles on [0, 1.5]^2
+        let side = 16;
+        let position = square_lattice(side);
+        let n = side * side;
+        let mut index = HashedGrid::new(SMOOTHING_RADIUS, 64);
+        index.populate(&position[0], &position[1], &position[2], n);
+        let particles = GridParticles {
+            position: [&position[0], &position[1], &position[2]],
+            rho: &vec![1.0; n],
+            material: &vec![0; n],
+        };
+        let mut grid = FieldGrid::new([-0.5, -0.5, 0.0], [2.0, 2.0, 0.0], [40, 40, 1]);
+        color_field(&particles, &[water()], &index, SMOOTHING_RADIUS, 2, &mut grid);
+
+        let lines = marching_squares(&grid, SURFACE_ISO_LEVEL);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].first(), lines[0].last());
+        for p in &lines[0] {
+            // Distance to the outline of the block
+            let inside = [p[0], p[1]].map(|c| c.min(1.5 - c));
+            let distance = if inside.iter().all(|&d| d > 0.0) {
+                inside[0].min(inside[1])
+            } else {
+                let gap = inside.map(|d| (-d).max(0.0));
+                (gap[0] * gap[0] + gap[1] * gap[1]).sqrt()
+            };
+            assert!(distance < SPACING, "{p:?}: {distance}");
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::elasticity::Elasticity;
+use crate::equation_of_state::{EquationOfStateModel, Linear};
+use crate::granular::DruckerPrager;
+use crate::material::{Material, MaterialKind};
+use crate::rheology::ViscosityModel;
+
+/// Particle spacing of the test lattices.
+pub const SPACING: f32 = 0.1;
+pub const SMOOTHING_RADIUS: f32 = 3.0 * SPACING;
+
+/// Inviscid fluid at unit rest density whose particles fill one lattice
+/// cell each.
+pub fn water() -> Material {
+    Material {
+        name: "water",
+        kind: MaterialKind::Fluid,
+        rest_density: 1.0,
+        mass: SPACING * SPACING,
+        viscosity: 0.0,
+        equation_of_state: EquationOfStateModel::Linear(Linear {
+            reference_density: 1.0,
+            sound_speed: 10.0,
+            background_pressure: 0.0,
+        }),
+        color: [1.0; 3],
+        conductivity: 0.0,
+        specific_heat: 1.0,
+        thermal_expansion: 0.0,
+        rheology: ViscosityModel::Newtonian,
+        elasticity: Elasticity::default(),
+        plasticity: DruckerPrager::default(),
+    }
+}
+
+/// Positions of `side` x `side` particles `SPACING` apart in the z = 0
+/// plane, particle `i * side + j` at `(i, j) * SPACING`.
+pub fn square_lattice(side: usize) -> [Vec<f32>; 3] {
+    let mut position = [Vec::new(), Vec::new(), Vec::new()];
+    for i in 0..side {
+        for j in 0..side {
+            position[0].push(i as f32 * SPACING);
+            position[1].push(j as f32 * SPACING);
+            position[2].push(0.0);
+        }
+    }
+    position
+}
+
+/// Half neighbor lists, `j > i`, of the particles within `SMOOTHING_RADIUS`.
+pub fn half_neighbor_lists(position: &[Vec<f32>; 3]) -> Vec<Vec<usize>> {
+    let n = position[0].len();
+    (0..n)
+        .map(|i| {
+            (i + 1..n)
+                .filter(|&j| {
+                    let r2: f32 = position.iter().map(|p| (p[i] - p[j]) * (p[i] - p[j])).sum();
+                    r2 < SMOOTHING_RADIUS * SMOOTHING_RADIUS
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Deterministic pseudo-random numbers in `[0, 1)` from a linear
+/// congruential generator.
+pub fn random_numbers(seed: u32) -> impl FnMut() -> f32 {
+    let mut state = seed;
+    move || {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// `n` points scattered uniformly over the box spanning `extent` along each
+/// axis; a flat axis gives a flat cloud.
+pub fn scattered_points(n: usize, seed: u32, extent: [[f32; 2]; 3]) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+    let mut next = random_numbers(seed);
+    let mut axis = |[lo, hi]: [f32; 2]| (0..n).map(|_| lo + (hi - lo) * next()).collect();
+    (axis(extent[0]), axis(extent[1]), axis(extent[2]))
+}
@@ -0,0 +1,178 @@
+use crate::elasticity::Matrix3;
+use crate::kernel::d_kernel_nd;
+use crate::material::Material;
+
+/// Constants of the sub-particle-scale (SPS) closure of Dalrymple & Rogers
+/// (2006), as used by DualSPHysics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubParticleScale {
+    /// Smagorinsky constant `C_s` of the eddy viscosity `(C_s dx)^2 |S|`
+    pub smagorinsky: f32,
+    /// Blin constant `C_I` of the isotropic part `2/3 C_I dx^2 |S|^2`
+    pub blin: f32,
+}
+
+/// SPS stress of every fluid particle from its velocity gradient,
+/// `tau = rho (2 nu_t (S - tr(S) I / 3) - 2/3 C_I dx^2 |S|^2 I)` with the eddy
+/// viscosity `nu_t = (C_s dx)^2 |S|` and `|S| = sqrt(2 S:S)`. The filter
+/// width `dx` is the rest particle spacing of the material.
+pub fn sps_stresses(
+    materials: &[Material],
+    material: &[usize],
+    rho: &[f32],
+    velocity_gradient: &[Matrix3],
+    closure: SubParticleScale,
+    dim: usize,
+    tau: &mut [Matrix3],
+) {
+    for (i, tau) in tau.iter_mut().enumerate() {
+        *tau = [[0.0; 3]; 3];
+        let material = &materials[material[i]];
+        if !material.is_fluid() || rho[i] <= 0.0 {
+            continue;
+        }
+
+        let l = &velocity_gradient[i];
+        let strain_rate: Matrix3 = std::array::from_fn(|a| std::array::from_fn(|b| 0.5 * (l[a][b] + l[b][a])));
+        let magnitude = (2.0 * strain_rate.iter().flatten().map(|s| s * s).sum::<f32>()).sqrt();
+        let volume_rate = strain_rate[0][0] + strain_rate[1][1] + strain_rate[2][2];
+
+        let spacing = (material.mass / material.rest_density).powf(1.0 / dim as f32);
+        let eddy_viscosity = (closure.smagorinsky * spacing).powi(2) * magnitude;
+        let isotropic = 2.0 / 3.0 * closure.blin * spacing * spacing * magnitude * magnitude;
+
+        for a in 0..3 {
+            for b in 0..3 {
+                let diagonal = if a == b { -2.0 * eddy_viscosity * volume_rate / 3.0 - isotropic } else { 0.0 };
+                tau[a][b] = rho[i] * (2.0 * eddy_viscosity * strain_rate[a][b] + diagonal);
+            }
+        }
+    }
+}
+
+/// Borrowed per-particle fields read by the SPS momentum term.
+pub struct TurbulentParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub tau: &'a [Matrix3],
+    pub material: &'a [usize],
+}
+
+/// Adds the divergence of the SPS stress between fluid particles,
+/// `dv_i/dt = sum_j m_j (tau_i / rho_i^2 + tau_j / rho_j^2) grad_i W_ij`.
+pub fn accumulate_sps_forces(
+    particles: &TurbulentParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    accel: [&mut [f32]; 3],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let rho = particles.rho;
+    let tau = particles.tau;
+
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        let material_i = &materials[particles.material[i]];
+        if !material_i.is_fluid() || rho[i] <= 0.0 {
+            continue;
+        }
+        for &j in neighbor_list {
+            let material_j = &materials[particles.material[j]];
+            if !material_j.is_fluid() || rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+            if r2 >= smoothing_radius * smoothing_radius || r2 == 0.0 {
+                continue;
+            }
+
+            let d = r2.sqrt();
+            let grad = dr.map(|c| c * d_kernel_nd(d as f64, inv_h, dim) as f32 / d);
+            let rho_i_sq = rho[i] * rho[i];
+            let rho_j_sq = rho[j] * rho[j];
+
+            // Force on i per unit mass of both particles; j feels the opposite
+            for a in 0..3 {
+                let flux: f32 = (0..3).map(|b| (tau[i][a][b] / rho_i_sq + tau[j][a][b] / rho_j_sq) * grad[b]).sum();
+                accel[a][i] += material_j.mass * flux;
+                accel[a][j] -= material_i.mass * flux;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::water;
+
+    // Constants recommended by Dalrymple & Rogers
+    const CLOSURE: SubParticleScale = SubParticleScale { smagorinsky: 0.12, blin: 0.0066 };
+
+    fn fluid(mass: f32) -> Material {
+        Material { mass, ..water() }
+    }
+
+    #[test]
+    fn simple_shear_stress() {
+        let spacing = 0.1;
+        let materials = [fluid(spacing * spacing)];
+        let rate = 3.0;
+
+        let mut gradient = [[0.0; 3]; 3];
+        gradient[0][1] = rate;
+        let mut tau = [[[0.0; 3]; 3]];
+        sps_stresses(&materials, &[0], &[2.0], &[gradient], CLOSURE, 2, &mut tau);
+
+        // |S| equals the shear rate in simple shear
+        let eddy_viscosity = (CLOSURE.smagorinsky * spacing).powi(2) * rate;
+        let isotropic = 2.0 / 3.0 * CLOSURE.blin * spacing * spacing * rate * rate;
+        assert!((tau[0][0][1] - 2.0 * eddy_viscosity * rate).abs() < 1e-6, "{:?}", tau[0]);
+        assert_eq!(tau[0][0][1], tau[0][1][0]);
+        for a in 0..3 {
+            assert!((tau[0][a][a] + 2.0 * isotropic).abs() < 1e-6, "{:?}", tau[0]);
+        }
+    }
+
+    #[test]
+    fn rigid_motion_is_stress_free() {
+        let materials = [fluid(0.01)];
+        let rotation = [[0.0, -2.0, 0.0], [2.0, 0.0, 0.0], [0.0, 0.0, 0.0]];
+        let mut tau = [[[1.0; 3]; 3]; 2];
+        sps_stresses(&materials, &[0, 0], &[1.0, 1.0], &[rotation, [[0.0; 3]; 3]], CLOSURE, 2, &mut tau);
+        assert_eq!(tau, [[[0.0; 3]; 3]; 2]);
+    }
+
+    #[test]
+    fn pair_forces_conserve_momentum() {
+        let materials = [fluid(0.01)];
+        let position = [vec![0.0, 0.1, 0.05], vec![0.0, 0.0, 0.08], vec![0.0; 3]];
+        let rho = [1.0, 1.1, 0.9];
+        let tau = [
+            [[-0.3, 0.5, 0.0], [0.5, -0.2, 0.0], [0.0, 0.0, -0.25]],
+            [[-0.1, -0.2, 0.0], [-0.2, -0.4, 0.0], [0.0, 0.0, -0.25]],
+            [[0.2, 0.1, 0.0], [0.1, -0.2, 0.0], [0.0, 0.0, 0.0]],
+        ];
+        let particles = TurbulentParticles {
+            position: [&position[0], &position[1], &position[2]],
+            rho: &rho,
+            tau: &tau,
+            material: &[0, 0, 0],
+        };
+        let neighbors = vec![vec![1, 2], vec![2], vec![]];
+        let mut accel = [vec![0.0; 3], vec![0.0; 3], vec![0.0; 3]];
+        let [ax, ay, az] = &mut accel;
+        accumulate_sps_forces(&particles, &materials, &neighbors, 0.3, 2, [ax, ay, az]);
+
+        for component in &accel[..2] {
+            assert!(component.iter().any(|a| a.abs() > 1e-3));
+        }
+        for component in &accel {
+            assert!(component.iter().sum::<f32>().abs() < 1e-5, "{component:?}");
+        }
+    }
+}
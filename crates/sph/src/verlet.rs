@@ -0,0 +1,141 @@
+/// Bookkeeping for Verlet neighbor lists. Lists are built with a cutoff of
+/// `smoothing_radius + skin` and stay valid until some particle has moved more
+/// than half the skin since the last rebuild.
+#[derive(Debug, Clone)]
+pub struct VerletList {
+    pub skin: f32,
+    pub reference_x: Vec<f32>,
+    pub reference_y: Vec<f32>,
+    pub reference_z: Vec<f32>,
+    pub valid: bool,
+}
+
+impl VerletList {
+    pub fn new(skin: f32, num_particles: usize) -> Self {
+        VerletList {
+            skin,
+            reference_x: vec![0.0; num_particles],
+            reference_y: vec![0.0; num_particles],
+            reference_z: vec![0.0; num_particles],
+            valid: false,
+        }
+    }
+
+    pub fn needs_rebuild(&self, px: &[f32], py: &[f32], pz: &[f32]) -> bool {
+        if !self.valid {
+            return true;
+        }
+
+        let max_displacement = max_displacement(
+            px, py, pz,
+            &self.reference_x, &self.reference_y, &self.reference_z,
+        );
+        max_displacement > 0.5 * self.skin
+    }
+
+    pub fn record_positions(&mut self, px: &[f32], py: &[f32], pz: &[f32]) {
+        let n = self.reference_x.len();
+        self.reference_x.copy_from_slice(&px[..n]);
+        self.reference_y.copy_from_slice(&py[..n]);
+        self.reference_z.copy_from_slice(&pz[..n]);
+        self.valid = true;
+    }
+}
+
+pub fn max_displacement(
+    px: &[f32],
+    py: &[f32],
+    pz: &[f32],
+    rx: &[f32],
+    ry: &[f32],
+    rz: &[f32],
+) -> f32 {
+    let mut max_sq = 0.0_f32;
+
+    for i in 0..rx.len() {
+        let dx = px[i] - rx[i];
+        let dy = py[i] - ry[i];
+        let dz = pz[i] - rz[i];
+        max_sq = max_sq.max(dx * dx + dy * dy + dz * dz);
+    }
+
+    max_sq.sqrt()
+}
+
+/// Drops candidate pairs from cell-based neighbor lists that lie further apart
+/// than `radius`.
+pub fn prune_neighbors(
+    px: &[f32],
+    py: &[f32],
+    pz: &[f32],
+    neighbors: &mut [Vec<usize>],
+    radius: f32,
+) {
+    let radius_sq = radius * radius;
+
+    for (i, neighbor_list) in neighbors.iter_mut().enumerate() {
+        neighbor_list.retain(|&j| {
+            let dx = px[i] - px[j];
+            let dy = py[i] - py[j];
+            let dz = pz[i] - pz[j];
+            dx * dx + dy * dy + dz * dz <= radius_sq
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_list_needs_rebuild() {
+        let list = VerletList::new(0.1, 2);
+        assert!(list.needs_rebuild(&[0.0, 1.0], &[0.0, 0.0], &[0.0, 0.0]));
+    }
+
+    #[test]
+    fn rebuild_threshold_is_half_skin() {
+        let mut list = VerletList::new(0.1, 2);
+        list.record_positions(&[0.0, 1.0], &[0.0, 0.0], &[0.0, 0.0]);
+
+        assert!(!list.needs_rebuild(&[0.04, 1.0], &[0.0, 0.0], &[0.0, 0.0]));
+        assert!(!list.needs_rebuild(&[0.0, 1.0], &[0.0, -0.049], &[0.0, 0.0]));
+        assert!(list.needs_rebuild(&[0.0, 1.06], &[0.0, 0.0], &[0.0, 0.0]));
+        assert!(list.needs_rebuild(&[0.04, 1.0], &[0.0, 0.0], &[0.04, 0.0]));
+    }
+
+    #[test]
+    fn prune_keeps_pairs_within_radius() {
+        let px = [0.0, 0.5, 1.2, 0.0];
+        let py = [0.0, 0.0, 0.0, 0.9];
+        let pz = [0.0, 0.0, 0.0, 0.0];
+        let mut neighbors = vec![vec![1, 2, 3], vec![2, 3], vec![3], vec![]];
+
+        prune_neighbors(&px, &py, &pz, &mut neighbors, 1.0);
+
+        assert_eq!(neighbors[0], vec![1, 3]);
+        assert_eq!(neighbors[1], vec![2]);
+        assert_eq!(neighbors[2], vec![]);
+    }
+
+    #[test]
+    fn list_with_skin_covers_pairs_until_rebuild() {
+        let cutoff = 1.0_f32;
+        let skin = 0.4_f32;
+        let mut px = vec![0.0_f32, 1.15, 0.5];
+        let py = vec![0.0_f32, 0.0, 0.6];
+        let pz = vec![0.0_f32; 3];
+
+        let mut neighbors = vec![vec![1, 2], vec![2], vec![]];
+        prune_neighbors(&px, &py, &pz, &mut neighbors, cutoff + skin);
+        let mut list = VerletList::new(skin, 3);
+        list.record_positions(&px, &py, &pz);
+        assert_eq!(neighbors[0], vec![1, 2]);
+
+        // Particle 1 drifts inside the cutoff without triggering a rebuild,
+        // and the list built with the skin still contains the pair.
+        px[1] = 0.98;
+        assert!(!list.needs_rebuild(&px, &py, &pz));
+        assert!(neighbors[0].contains(&1));
+    }
+}
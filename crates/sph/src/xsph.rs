@@ -0,0 +1,103 @@
+use crate::kernel::kernel_nd;
+use crate::material::Material;
+
+/// Borrowed per-particle fields read by the XSPH velocity correction.
+pub struct XsphParticles<'a> {
+    pub position: [&'a [f32]; 3],
+    pub velocity: [&'a [f32]; 3],
+    pub rho: &'a [f32],
+    pub material: &'a [usize],
+}
+
+/// XSPH correction of Monaghan (1989) to the velocity fluid particles are
+/// moved with, `dv_i = epsilon sum_j m_j / rho_ij (v_j - v_i) W_ij` with the
+/// mean density `rho_ij`. It pulls each particle towards the mean velocity of
+/// its neighbors, so the particles travel in an orderly way; only positions
+/// are moved with it, so momentum is unchanged.
+pub fn xsph_corrections(
+    particles: &XsphParticles,
+    materials: &[Material],
+    neighbors: &[Vec<usize>],
+    smoothing_radius: f32,
+    dim: usize,
+    epsilon: f32,
+    correction: &mut [[f32; 3]],
+) {
+    let inv_h = 1.0 / smoothing_radius as f64;
+    let [x, y, z] = particles.position;
+    let [vx, vy, vz] = particles.velocity;
+    let rho = particles.rho;
+
+    correction.fill([0.0; 3]);
+    for (i, neighbor_list) in neighbors.iter().enumerate() {
+        let material_i = &materials[particles.material[i]];
+        if !material_i.is_fluid() {
+            continue;
+        }
+        for &j in neighbor_list {
+            let material_j = &materials[particles.material[j]];
+            if !material_j.is_fluid() || rho[i] + rho[j] <= 0.0 {
+                continue;
+            }
+
+            let dr = [x[i] - x[j], y[i] - y[j], z[i] - z[j]];
+            let r2 = dr[0] * dr[0] + dr[1] * dr[1] + dr[2] * dr[2];
+            if r2 >= smoothing_radius * smoothing_radius {
+                continue;
+            }
+
+            let w = kernel_nd(r2.sqrt() as f64, inv_h, dim) as f32;
+            let scale = epsilon * w / (0.5 * (rho[i] + rho[j]));
+            let dv = [vx[j] - vx[i], vy[j] - vy[i], vz[j] - vz[i]];
+
+            // W_ji = W_ij and v_i - v_j = -(v_j - v_i)
+            for a in 0..3 {
+                correction[i][a] += material_j.mass * scale * dv[a];
+                correction[j][a] -= material_i.mass * scale * dv[a];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{half_neighbor_lists, square_lattice, water, SMOOTHING_RADIUS};
+
+    const SIDE: usize = 10;
+
+    /// Corrections on a square block of water moving with `velocity`.
+    fn block_corrections(velocity: impl Fn(usize) -> [f32; 2]) -> (Vec<[f32; 2]>, Vec<[f32; 3]>) {
+        let position = square_lattice(SIDE);
+        let n = SIDE * SIDE;
+        let neighbors = half_neighbor_lists(&position);
+        let v: Vec<[f32; 2]> = (0..n).map(velocity).collect();
+        let field = [v.iter().map(|v| v[0]).collect::<Vec<_>>(), v.iter().map(|v| v[1]).collect(), vec![0.0; n]];
+        let particles = XsphParticles {
+            position: [&position[0], &position[1], &position[2]],
+            velocity: [&field[0], &field[1], &field[2]],
+            rho: &vec![1.0; n],
+            material: &vec![0; n],
+        };
+        let mut correction = vec![[1.0; 3]; n];
+        xsph_corrections(&particles, &[water()], &neighbors, SMOOTHING_RADIUS, 2, 0.5, &mut correction);
+        (v, correction)
+    }
+
+    #[test]
+    fn uniform_flow_is_not_corrected() {
+        let (_, correction) = block_corrections(|_| [1.5, -0.5]);
+        assert!(correction.iter().flatten().all(|c| c.abs() < 1e-6));
+    }
+
+    #[test]
+    fn jitter_is_damped_without_changing_momentum() {
+        let jitter = |i: usize| [1.0 + if i.is_multiple_of(3) { 0.2 } else { -0.1 }, 0.0];
+        let (velocity, correction) = block_corrections(jitter);
+        for (i, (v, dv)) in velocity.iter().zip(&correction).enumerate() {
+            assert!((v[0] - 1.0) * dv[0] < 0.0, "particle {i}: {v:?} {dv:?}");
+        }
+        let momentum: f32 = correction.iter().map(|dv| dv[0]).sum();
+        assert!(momentum.abs() < 1e-5, "{momentum}");
+    }
+}
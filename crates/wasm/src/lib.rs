@@ -12,7 +12,7 @@ fn get_state() -> &'static Mutex<State> {
 #[wasm_bindgen]
 pub fn update() {
     let mut state_guard = get_state().lock().unwrap();
-    simulation::update(&mut *state_guard);
+    simulation::update(&mut state_guard);
 }
 
 #[wasm_bindgen]
@@ -43,4 +43,58 @@ pub fn get_z_ptr() -> *const f32 {
 #[wasm_bindgen]
 pub fn get_rho_ptr() -> *const f32 {
     get_state().lock().unwrap().rho.as_ptr()
-}
\ No newline at end of file
+}
+
+#[wasm_bindgen]
+pub fn get_temperature_ptr() -> *const f32 {
+    get_state().lock().unwrap().temperature.as_ptr()
+}
+
+#[wasm_bindgen]
+pub fn get_material_ptr() -> *const usize {
+    get_state().lock().unwrap().material.as_ptr()
+}
+
+/// One byte per particle, 1 on the free surface. Only updated with free
+/// surface detection or particle shifting enabled.
+#[wasm_bindgen]
+pub fn get_free_surface_ptr() -> *const bool {
+    get_state().lock().unwrap().free_surface.as_ptr()
+}
+
+/// Outward unit normals of the surface particles as consecutive x, y, z.
+#[wasm_bindgen]
+pub fn get_surface_normal_ptr() -> *const f32 {
+    get_state().lock().unwrap().surface_normal.as_flattened().as_ptr()
+}
+
+/// Vorticity of every particle as consecutive x, y, z; only z in 2D. Only
+/// updated with vorticity output or confinement enabled.
+#[wasm_bindgen]
+pub fn get_vorticity_ptr() -> *const f32 {
+    get_state().lock().unwrap().vorticity.as_flattened().as_ptr()
+}
+
+/// Color of the material at `index`; empty for an unknown index.
+#[wasm_bindgen]
+pub fn material_color(index: usize) -> Vec<f32> {
+    let state = get_state().lock().unwrap();
+    state.materials.as_slice().get(index).map(|material| material.color.to_vec()).unwrap_or_default()
+}
+
+#[wasm_bindgen]
+pub fn neighbor_rebuild_frequency() -> f64 {
+    get_state().lock().unwrap().diagnostics.neighbor_rebuild_frequency()
+}
+
+#[wasm_bindgen]
+pub fn particles_within(x: f32, y: f32, radius: f32) -> Vec<u32> {
+    let state = get_state().lock().unwrap();
+    state.particles_within([x, y, 0.0], radius).into_iter().map(|i| i as u32).collect()
+}
+
+#[wasm_bindgen]
+pub fn nearest_particles(x: f32, y: f32, k: usize) -> Vec<u32> {
+    let state = get_state().lock().unwrap();
+    state.nearest_particles([x, y, 0.0], k).into_iter().map(|i| i as u32).collect()
+}